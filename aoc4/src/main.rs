@@ -1,7 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
 use std::fs::File;
-use std::io::{Read};
+use std::io::{self, BufWriter, Read, Write};
+use std::process;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use rayon::prelude::*;
 use regex::Regex;
+use serde::Serialize;
 
 pub static REQUIRED_FIELDS_PART_ONE: [&str;7] = [
     "byr",
@@ -13,13 +23,77 @@ pub static REQUIRED_FIELDS_PART_ONE: [&str;7] = [
     "pid",
 ];
 
+pub static OPTIONAL_FIELDS: [&str;1] = [
+    "cid",
+];
+
+pub static EYE_COLORS: [&str;7] = [
+    "amb",
+    "blu",
+    "brn",
+    "gry",
+    "grn",
+    "hzl",
+    "oth",
+];
+
+// Compiled once and shared across threads so bulk validation doesn't pay for
+// building the rules on every passport.
+static HAIR_COLOR_RULE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^#[[0-9][a-f]]{6}$").unwrap());
+static PASSPORT_ID_RULE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[0-9]{9}$").unwrap());
+static EYE_COLOR_RULE: LazyLock<HashSet<&'static str>> = LazyLock::new(|| EYE_COLORS.iter().cloned().collect());
+
 fn main() {
     test_validate_credential();
+    test_read_credential_records();
     test_count_valid();
     test_validate_passport();
+    test_passport_try_from_errors();
     test_validate_passports_count();
+    test_normalize_credential();
+    test_write_passports();
+    test_count_valid_parallel();
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "export" {
+        if args.len() < 5 {
+            eprintln!("usage: {} export <csv|jsonl|kv> <output> <rejects> [input]", args[0]);
+            process::exit(2);
+        }
+        let format = args[2].parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        });
+        let input = args.get(5).map_or("src/input.txt", |input| input.as_str());
+        match export(input, format, &args[3], &args[4]) {
+            Ok((valid, rejected)) => println!("Exported {} passports, rejected {}", valid, rejected),
+            Err(e) => {
+                eprintln!("export failed: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if args.len() > 1 && args[1] == "bench" {
+        let count = args.get(2).map_or(Ok(1_000_000), |count| count.parse()).unwrap_or_else(|e| {
+            eprintln!("invalid record count: {}", e);
+            process::exit(2);
+        });
+        let filename = args.get(3).map_or("target/generated_credentials.txt", |filename| filename.as_str());
+        if let Err(e) = benchmark(filename, count) {
+            eprintln!("benchmark failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
-    let data = read_data("src/input.txt");
+    let records = read_data("src/input.txt");
+    for record in &records {
+        for issue in &record.issues {
+            eprintln!("record at line {}: {}", record.line, issue);
+        }
+    }
+    let data = records.into_iter().map(|record| record.fields).collect();
 
     let round_1_valid = count_valid(&data, &REQUIRED_FIELDS_PART_ONE.to_vec());
     println!("Valid records in round 1: {}", round_1_valid);
@@ -29,13 +103,13 @@ fn main() {
 }
 
 
-fn read_data(filename: &str) -> Vec<HashMap<String, String>> {
+fn read_data(filename: &str) -> Vec<CredentialRecord> {
     let mut file = File::open(filename)
         .expect("Something went wrong reading the file");
     let mut records_block = String::new();
     file.read_to_string(&mut records_block).expect("Error reading data");
 
-    let records = build_credentials(records_block);
+    let records = read_credential_records(&records_block);
 
     return records;
 
@@ -63,20 +137,43 @@ fn count_valid(credentials: &Vec<HashMap<String, String>>, required_fields: &Vec
     return valid;
 }
 
+fn validate_credential_v2(credential: &HashMap<String, String>, required_fields: &Vec<&str>) -> bool {
+    if !validate_credential(credential, required_fields) {
+        return false;
+    }
+    return Passport::try_from(credential).is_ok_and(|passport| passport.validate_all());
+}
+
 fn count_valid_v2(credentials: &Vec<HashMap<String, String>>, required_fields: &Vec<&str>) -> i64 {
-    let validate = |credential| validate_credential(credential, required_fields);
+    let validate = |credential| validate_credential_v2(credential, required_fields);
     let mut valid = 0;
     for credential in credentials {
         if validate(credential) {
-            let passport = Passport::new(credential);
-            if passport.validate_all() {
-                valid+=1;
-            }
+            valid+=1;
         }
     }
     return valid;
 }
 
+fn count_valid_v2_parallel(credentials: &[HashMap<String, String>], required_fields: &Vec<&str>) -> i64 {
+    return credentials.par_iter()
+        .filter(|credential| validate_credential_v2(credential, required_fields))
+        .count() as i64;
+}
+
+/// Parses and validates straight from the raw block, so large dumps never
+/// hold every parsed record in memory at once.
+fn count_valid_block(block: &str, required_fields: &Vec<&str>, parallel: bool) -> i64 {
+    let blocks = record_blocks(block);
+    let validate = |(line, text): &(usize, &str)| {
+        validate_credential_v2(&parse_credential_record(*line, text).fields, required_fields)
+    };
+    if parallel {
+        return blocks.par_iter().filter(|record| validate(record)).count() as i64;
+    }
+    return blocks.iter().filter(|record| validate(record)).count() as i64;
+}
+
 struct Passport {
     byr: u16,
     iyr: u16,
@@ -87,20 +184,54 @@ struct Passport {
     pid: String,
 }
 
-impl Passport {
-    pub fn new(record: &HashMap<String, String>) -> Passport {
-            Passport {
-                byr: record.get("byr").unwrap().parse().unwrap(),
-                iyr: record.get("iyr").unwrap().parse().unwrap(),
-                eyr: record.get("eyr").unwrap().parse().unwrap(),
-                hgt: record.get("hgt").unwrap().to_string(),
-                hcl: record.get("hcl").unwrap().to_string(),
-                ecl: record.get("ecl").unwrap().to_string(),
-                pid: record.get("pid").unwrap().to_string(),
+#[derive(Debug, PartialEq)]
+enum PassportError {
+    MissingField(String),
+    InvalidNumber { field: String, value: String },
+}
+
+impl fmt::Display for PassportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassportError::MissingField(field) => write!(f, "missing field {}", field),
+            PassportError::InvalidNumber { field, value } => {
+                write!(f, "field {} is not a number: {:?}", field, value)
             }
+        }
+    }
+}
+
+impl std::error::Error for PassportError {}
+
+fn required_field<'a>(record: &'a HashMap<String, String>, field: &str) -> Result<&'a String, PassportError> {
+    return record.get(field).ok_or_else(|| PassportError::MissingField(field.to_string()));
+}
+
+fn parse_year(record: &HashMap<String, String>, field: &str) -> Result<u16, PassportError> {
+    let value = required_field(record, field)?;
+    return value.parse().map_err(|_| PassportError::InvalidNumber {
+        field: field.to_string(),
+        value: value.to_string(),
+    });
+}
 
+impl TryFrom<&HashMap<String, String>> for Passport {
+    type Error = PassportError;
+
+    fn try_from(record: &HashMap<String, String>) -> Result<Passport, PassportError> {
+        Ok(Passport {
+            byr: parse_year(record, "byr")?,
+            iyr: parse_year(record, "iyr")?,
+            eyr: parse_year(record, "eyr")?,
+            hgt: required_field(record, "hgt")?.to_string(),
+            hcl: required_field(record, "hcl")?.to_string(),
+            ecl: required_field(record, "ecl")?.to_string(),
+            pid: required_field(record, "pid")?.to_string(),
+        })
     }
+}
 
+impl Passport {
     pub fn validate_all(&self) -> bool {
         return self.validate_birth_year()
             && self.validate_expiration_year()
@@ -124,61 +255,473 @@ impl Passport {
     }
 
     pub fn validate_height(&self) -> bool {
-        if self.hgt.ends_with("cm") {
-            let height: i16 = self.hgt.strip_suffix("cm").unwrap().parse().unwrap();
-            return height >= 150 && height <= 193;
+        if let Some(height) = self.hgt.strip_suffix("cm") {
+            return height.parse::<i16>().is_ok_and(|height| height >= 150 && height <= 193);
         }
-        else if self.hgt.ends_with("in") {
-            let height: i16 = self.hgt.strip_suffix("in").unwrap().parse().unwrap();
-            return height >= 59 && height <= 76;
+        else if let Some(height) = self.hgt.strip_suffix("in") {
+            return height.parse::<i16>().is_ok_and(|height| height >= 59 && height <= 76);
         }
         return false;
     }
 
     pub fn validate_hair_color(&self) -> bool {
-        let re = Regex::new(r"^#[[0-9][a-f]]{6}$").unwrap();
-        return re.is_match(&self.hcl);
+        return HAIR_COLOR_RULE.is_match(&self.hcl);
     }
 
     pub fn validate_eye_color(&self) -> bool {
-        let valid_colors: HashMap<String, bool> = [
-            ("amb".to_string(), true),
-            ("blu".to_string(), true),
-            ("brn".to_string(), true),
-            ("gry".to_string(), true),
-            ("grn".to_string(), true),
-            ("hzl".to_string(), true),
-            ("oth".to_string(), true),
-        ].iter().cloned().collect();
-
-        return valid_colors.contains_key(&self.ecl);
+        return EYE_COLOR_RULE.contains(self.ecl.as_str());
     }
 
     pub fn validate_passport_id(&self) -> bool {
-        let re = Regex::new(r"^[0-9]{9}$").unwrap();
-        return re.is_match(&self.pid);
+        return PASSPORT_ID_RULE.is_match(&self.pid);
     }
 }
 
-fn build_credential(line: String) -> HashMap<String, String> {
-    let mut credential = HashMap::new();
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Height {
+    Centimetres(u16),
+    Inches(u16),
+}
 
-    for field in line.split_whitespace() {
-        let key_value = field.split(':').collect::<Vec<&str>>();
-        credential.entry(key_value[0].to_string()).or_insert(key_value[1].to_string());
+impl Height {
+    pub fn parse(value: &str) -> Option<Height> {
+        if let Some(height) = value.strip_suffix("cm") {
+            return height.parse().ok().map(Height::Centimetres);
+        }
+        else if let Some(height) = value.strip_suffix("in") {
+            return height.parse().ok().map(Height::Inches);
+        }
+        return None;
     }
 
-    return credential;
+    /// Exact to the hundredth: inches are scaled in integers first, since
+    /// `2.54` has no exact float and `66.0 * 2.54` is `167.64000000000001`.
+    pub fn centimetres(&self) -> f64 {
+        match self {
+            Height::Centimetres(height) => *height as f64,
+            Height::Inches(height) => (*height as u32 * 254) as f64 / 100.0,
+        }
+    }
 }
 
-fn build_credentials(block: String) -> Vec<HashMap<String, String>> {
-    let mut credentials = Vec::new();
+impl fmt::Display for Height {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Height::Centimetres(height) => write!(f, "{}cm", height),
+            Height::Inches(height) => write!(f, "{}in", height),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct NormalizedPassport {
+    byr: u16,
+    iyr: u16,
+    eyr: u16,
+    hgt: Height,
+    hcl: (u8, u8, u8),
+    ecl: String,
+    pid: String,
+    cid: Option<String>,
+}
+
+impl NormalizedPassport {
+    pub fn hair_color_hex(&self) -> String {
+        return format!("#{:02x}{:02x}{:02x}", self.hcl.0, self.hcl.1, self.hcl.2);
+    }
+}
+
+/// One CSV row; the csv crate takes the header from the field names.
+#[derive(Serialize)]
+struct PassportCsvRow<'a> {
+    byr: u16,
+    iyr: u16,
+    eyr: u16,
+    height_cm: f64,
+    hair_r: u8,
+    hair_g: u8,
+    hair_b: u8,
+    ecl: &'a str,
+    pid: &'a str,
+    cid: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct PassportJsonLine<'a> {
+    byr: u16,
+    iyr: u16,
+    eyr: u16,
+    height_cm: f64,
+    hair_rgb: (u8, u8, u8),
+    ecl: &'a str,
+    pid: &'a str,
+    cid: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct RejectedRecord {
+    line: usize,
+    fields: BTreeMap<String, String>,
+    reasons: Vec<String>,
+}
+
+fn parse_hair_color(hcl: &str) -> Option<(u8, u8, u8)> {
+    let hex = hcl.strip_prefix('#')?;
+    let channel = |range: std::ops::Range<usize>| u8::from_str_radix(hex.get(range)?, 16).ok();
+    return Some((channel(0..2)?, channel(2..4)?, channel(4..6)?));
+}
+
+/// Validates a record against every part-two rule and returns the typed
+/// passport, or every reason the record was refused.
+fn normalize_credential(record: &HashMap<String, String>) -> Result<NormalizedPassport, Vec<String>> {
+    let missing: Vec<String> = REQUIRED_FIELDS_PART_ONE.iter()
+        .filter(|field| !record.contains_key(**field))
+        .map(|field| PassportError::MissingField(field.to_string()).to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(missing);
+    }
+
+    let passport = Passport::try_from(record).map_err(|e| vec![e.to_string()])?;
+    let checks: [(&str, &String, bool); 7] = [
+        ("byr", &record["byr"], passport.validate_birth_year()),
+        ("iyr", &record["iyr"], passport.validate_issue_year()),
+        ("eyr", &record["eyr"], passport.validate_expiration_year()),
+        ("hgt", &passport.hgt, passport.validate_height()),
+        ("hcl", &passport.hcl, passport.validate_hair_color()),
+        ("ecl", &passport.ecl, passport.validate_eye_color()),
+        ("pid", &passport.pid, passport.validate_passport_id()),
+    ];
+    let reasons: Vec<String> = checks.iter()
+        .filter(|(_, _, valid)| !valid)
+        .map(|(field, value, _)| format!("invalid {}: {:?}", field, value))
+        .collect();
+    if !reasons.is_empty() {
+        return Err(reasons);
+    }
+
+    Ok(NormalizedPassport {
+        byr: passport.byr,
+        iyr: passport.iyr,
+        eyr: passport.eyr,
+        hgt: Height::parse(&passport.hgt).unwrap(),
+        hcl: parse_hair_color(&passport.hcl).unwrap(),
+        ecl: passport.ecl,
+        pid: passport.pid,
+        cid: record.get("cid").cloned(),
+    })
+}
+
+fn normalize_records(records: &[CredentialRecord]) -> (Vec<NormalizedPassport>, Vec<RejectedRecord>) {
+    let mut passports = Vec::new();
+    let mut rejects = Vec::new();
+    for record in records {
+        match normalize_credential(&record.fields) {
+            Ok(passport) => passports.push(passport),
+            Err(reasons) => rejects.push(RejectedRecord {
+                line: record.line,
+                fields: record.fields.iter().map(|(key, value)| (key.clone(), value.clone())).collect(),
+                // What the reader had to drop or ignore often explains why
+                // the record then failed validation, so list it first.
+                reasons: record.issues.iter().map(|issue| issue.to_string()).chain(reasons).collect(),
+            }),
+        }
+    }
+    return (passports, rejects);
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ExportFormat {
+    Csv,
+    JsonLines,
+    KeyValue,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<ExportFormat, String> {
+        match format {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            "kv" => Ok(ExportFormat::KeyValue),
+            _ => Err(format!("unknown format {:?}, expected csv, jsonl or kv", format)),
+        }
+    }
+}
+
+fn write_passports<W: Write>(out: &mut W, passports: &[NormalizedPassport], format: ExportFormat) -> io::Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for passport in passports {
+                writer.serialize(PassportCsvRow {
+                    byr: passport.byr,
+                    iyr: passport.iyr,
+                    eyr: passport.eyr,
+                    height_cm: passport.hgt.centimetres(),
+                    hair_r: passport.hcl.0,
+                    hair_g: passport.hcl.1,
+                    hair_b: passport.hcl.2,
+                    ecl: &passport.ecl,
+                    pid: &passport.pid,
+                    cid: passport.cid.as_deref(),
+                })?;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::JsonLines => {
+            for passport in passports {
+                serde_json::to_writer(&mut *out, &PassportJsonLine {
+                    byr: passport.byr,
+                    iyr: passport.iyr,
+                    eyr: passport.eyr,
+                    height_cm: passport.hgt.centimetres(),
+                    hair_rgb: passport.hcl,
+                    ecl: &passport.ecl,
+                    pid: &passport.pid,
+                    cid: passport.cid.as_deref(),
+                })?;
+                writeln!(out)?;
+            }
+        }
+        ExportFormat::KeyValue => {
+            for passport in passports {
+                write!(out, "byr:{} iyr:{} eyr:{} hgt:{} hcl:{} ecl:{} pid:{}",
+                    passport.byr, passport.iyr, passport.eyr, passport.hgt,
+                    passport.hair_color_hex(), passport.ecl, passport.pid)?;
+                if let Some(cid) = &passport.cid {
+                    write!(out, " cid:{}", cid)?;
+                }
+                writeln!(out)?;
+                writeln!(out)?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+fn write_rejects<W: Write>(out: &mut W, rejects: &[RejectedRecord]) -> io::Result<()> {
+    for reject in rejects {
+        serde_json::to_writer(&mut *out, reject)?;
+        writeln!(out)?;
+    }
+    return Ok(());
+}
+
+fn export(input: &str, format: ExportFormat, output: &str, rejects_output: &str) -> io::Result<(usize, usize)> {
+    let records = read_data(input);
+    let (passports, rejects) = normalize_records(&records);
+
+    let mut out = BufWriter::new(File::create(output)?);
+    write_passports(&mut out, &passports, format)?;
+    out.flush()?;
+
+    let mut rejects_out = BufWriter::new(File::create(rejects_output)?);
+    write_rejects(&mut rejects_out, &rejects)?;
+    rejects_out.flush()?;
+
+    return Ok((passports.len(), rejects.len()));
+}
+
+
+/// Small xorshift generator so generated credential dumps are reproducible
+/// without pulling in a random number crate.
+struct RecordGenerator {
+    state: u64,
+}
+
+impl RecordGenerator {
+    pub fn new(seed: u64) -> RecordGenerator {
+        RecordGenerator { state: seed.max(1) }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        return self.state;
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        return self.next() % bound;
+    }
+
+    pub fn record(&mut self) -> String {
+        let mut fields = Vec::with_capacity(8);
+        fields.push(format!("byr:{}", 1900 + self.below(120)));
+        fields.push(format!("iyr:{}", 2005 + self.below(20)));
+        fields.push(format!("eyr:{}", 2015 + self.below(20)));
+        fields.push(match self.below(4) {
+            0 => format!("hgt:{}in", 50 + self.below(35)),
+            1 => format!("hgt:{}", 140 + self.below(60)),
+            _ => format!("hgt:{}cm", 140 + self.below(60)),
+        });
+        fields.push(if self.below(8) == 0 {
+            format!("hcl:{:06x}", self.below(0x1000000))
+        }
+        else {
+            format!("hcl:#{:06x}", self.below(0x1000000))
+        });
+        fields.push(if self.below(8) == 0 {
+            "ecl:zzz".to_string()
+        }
+        else {
+            format!("ecl:{}", EYE_COLORS[self.below(EYE_COLORS.len() as u64) as usize])
+        });
+        fields.push(format!("pid:{:0width$}", self.below(1_000_000_000), width = 9 + (self.below(10) == 0) as usize));
+        if self.below(2) == 0 {
+            fields.push(format!("cid:{}", self.below(350)));
+        }
+        if self.below(10) == 0 {
+            fields.remove(self.below(fields.len() as u64) as usize);
+        }
+
+        let split = self.below(fields.len() as u64) as usize;
+        return format!("{}\n{}", fields[..split].join(" "), fields[split..].join(" "));
+    }
+}
+
+fn generate_records<W: Write>(out: &mut W, count: usize, seed: u64) -> io::Result<()> {
+    let mut generator = RecordGenerator::new(seed);
+    for index in 0..count {
+        if index > 0 {
+            writeln!(out)?;
+        }
+        writeln!(out, "{}", generator.record().trim())?;
+    }
+    return Ok(());
+}
+
+fn records_per_second(records: usize, elapsed: Duration) -> f64 {
+    return records as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+}
+
+fn benchmark(filename: &str, count: usize) -> io::Result<()> {
+    let started = Instant::now();
+    let mut out = BufWriter::new(File::create(filename)?);
+    generate_records(&mut out, count, 2020)?;
+    out.flush()?;
+    println!("generated {} records in {:?}", count, started.elapsed());
+
+    let mut block = String::new();
+    File::open(filename)?.read_to_string(&mut block)?;
+    let records = record_blocks(&block).len();
+    let required_fields = REQUIRED_FIELDS_PART_ONE.to_vec();
+
+    let started = Instant::now();
+    let serial_valid = count_valid_block(&block, &required_fields, false);
+    let elapsed = started.elapsed();
+    println!("serial: {} of {} valid in {:?} ({:.0} records/s)",
+        serial_valid, records, elapsed, records_per_second(records, elapsed));
+
+    let started = Instant::now();
+    let parallel_valid = count_valid_block(&block, &required_fields, true);
+    let elapsed = started.elapsed();
+    println!("parallel ({} threads): {} of {} valid in {:?} ({:.0} records/s)",
+        rayon::current_num_threads(), parallel_valid, records, elapsed, records_per_second(records, elapsed));
+
+    return Ok(());
+}
+
+#[derive(Debug, PartialEq)]
+enum RecordIssue {
+    MalformedToken(String),
+    DuplicateKey { key: String, kept: String, ignored: String },
+    UnknownField(String),
+}
+
+impl fmt::Display for RecordIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordIssue::MalformedToken(token) => write!(f, "token {:?} is not key:value", token),
+            RecordIssue::DuplicateKey { key, kept, ignored } => {
+                write!(f, "duplicate key {} (kept {:?}, ignored {:?})", key, kept, ignored)
+            }
+            RecordIssue::UnknownField(key) => write!(f, "unknown field {}", key),
+        }
+    }
+}
+
+struct CredentialRecord {
+    line: usize,
+    fields: HashMap<String, String>,
+    issues: Vec<RecordIssue>,
+}
+
+fn is_known_field(key: &str) -> bool {
+    return REQUIRED_FIELDS_PART_ONE.contains(&key) || OPTIONAL_FIELDS.contains(&key);
+}
+
+fn parse_credential_record(line: usize, text: &str) -> CredentialRecord {
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut issues = Vec::new();
 
-    for record in block.split("\n\n"){
-        credentials.push(build_credential(record.to_string()));
+    for token in text.split_whitespace() {
+        let (key, value) = match token.split_once(':') {
+            Some((key, value)) if !key.is_empty() => (key, value),
+            _ => {
+                issues.push(RecordIssue::MalformedToken(token.to_string()));
+                continue;
+            }
+        };
+        if !is_known_field(key) {
+            issues.push(RecordIssue::UnknownField(key.to_string()));
+        }
+        match fields.entry(key.to_string()) {
+            Entry::Occupied(existing) => issues.push(RecordIssue::DuplicateKey {
+                key: key.to_string(),
+                kept: existing.get().to_string(),
+                ignored: value.to_string(),
+            }),
+            Entry::Vacant(slot) => {
+                slot.insert(value.to_string());
+            }
+        }
+    }
+
+    return CredentialRecord { line, fields, issues };
+}
+
+/// Splits a block into records on lines that are empty once trimmed, so CRLF
+/// endings, whitespace-only separators and runs of blank lines all work.
+/// Each record is returned with the 1-based line it starts on, borrowing
+/// from `block`.
+fn record_blocks(block: &str) -> Vec<(usize, &str)> {
+    let mut blocks = Vec::new();
+    let mut start: Option<(usize, usize)> = None;
+    let mut offset = 0;
+
+    for (index, line) in block.split_inclusive('\n').enumerate() {
+        if line.trim().is_empty() {
+            if let Some((start_line, start_offset)) = start.take() {
+                blocks.push((start_line, &block[start_offset..offset]));
+            }
+        }
+        else if start.is_none() {
+            start = Some((index + 1, offset));
+        }
+        offset += line.len();
+    }
+    if let Some((start_line, start_offset)) = start {
+        blocks.push((start_line, &block[start_offset..]));
     }
 
-    return credentials;
+    return blocks;
+}
+
+fn read_credential_records(block: &str) -> Vec<CredentialRecord> {
+    return record_blocks(block).into_iter()
+        .map(|(line, text)| parse_credential_record(line, text))
+        .collect();
+}
+
+fn build_credential(line: String) -> HashMap<String, String> {
+    return parse_credential_record(1, &line).fields;
+}
+
+fn build_credentials(block: String) -> Vec<HashMap<String, String>> {
+    return read_credential_records(&block).into_iter().map(|record| record.fields).collect();
 }
 
 fn test_validate_credential() {
@@ -202,6 +745,31 @@ fn test_validate_credential() {
 
 }
 
+fn test_read_credential_records() {
+    let block = "ecl:gry pid:860033327 eyr:2020\r\nhcl:#fffffd byr:1937\r\n\r\niyr:2013 ecl:amb \n   \t\npid:028048884\n\n\n\nhcl:#cfa07d byr:1929\n";
+    let records = read_credential_records(block);
+    assert_eq!(records.len(), 4);
+    assert_eq!(records.iter().map(|record| record.line).collect::<Vec<usize>>(), vec![1, 4, 6, 10]);
+    assert_eq!(records[0].fields.len(), 5);
+    assert_eq!(records[0].fields.get("byr"), Some(&"1937".to_string()));
+    assert!(records.iter().all(|record| record.issues.is_empty()));
+
+    let record = parse_credential_record(1, "ecl:gry byr pid:860033327 ecl:blu foo:bar :x");
+    assert_eq!(record.fields.get("ecl"), Some(&"gry".to_string()));
+    assert_eq!(record.issues, vec![
+        RecordIssue::MalformedToken("byr".to_string()),
+        RecordIssue::DuplicateKey {
+            key: "ecl".to_string(),
+            kept: "gry".to_string(),
+            ignored: "blu".to_string(),
+        },
+        RecordIssue::UnknownField("foo".to_string()),
+        RecordIssue::MalformedToken(":x".to_string()),
+    ]);
+
+    assert_eq!(build_credentials("\n\n\n".to_string()).len(), 0);
+}
+
 fn test_count_valid() {
     let credential_block = 
 "ecl:gry pid:860033327 eyr:2020 hcl:#fffffd
@@ -225,40 +793,69 @@ iyr:2011 ecl:brn hgt:59in".to_string();
 fn test_validate_passport() {
     let credential = build_credential("eyr:1972 cid:100
     hcl:#18171d ecl:amb hgt:170 pid:186cm iyr:2018 byr:1926".to_string());
-    let passport = Passport::new(&credential);
+    let passport = Passport::try_from(&credential).unwrap();
     assert_eq!(passport.validate_all(), false);
 
     let credential_2 = build_credential("pid:087499704 
     hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980
     hcl:#623a2f".to_string());
-    let passport_2 = Passport::new(&credential_2);
+    let passport_2 = Passport::try_from(&credential_2).unwrap();
     assert_eq!(passport_2.validate_all(), true);
 
     let credential_2 = build_credential("pid:087499704
     hgt:74in ecl:grn iyr:2012 eyr:20300 byr:1980
     hcl:#623a2f".to_string());
-    let passport_2 = Passport::new(&credential_2);
+    let passport_2 = Passport::try_from(&credential_2).unwrap();
     assert_eq!(passport_2.validate_all(), false);
 
     let credential_2 = build_credential("pid:087499704
     hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980
     hcl:#623a2x".to_string());
-    let passport_2 = Passport::new(&credential_2);
+    let passport_2 = Passport::try_from(&credential_2).unwrap();
     assert_eq!(passport_2.validate_all(), false);
 
     let credential_2 = build_credential("pid:0874997049 
     hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980
     hcl:#623a2f".to_string());
-    let passport_2 = Passport::new(&credential_2);
+    let passport_2 = Passport::try_from(&credential_2).unwrap();
     assert_eq!(passport_2.validate_all(), false);
 
     let credential_2 = build_credential("pid:087499704 
     hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980
     hcl:#623a2f4".to_string());
-    let passport_2 = Passport::new(&credential_2);
+    let passport_2 = Passport::try_from(&credential_2).unwrap();
     assert_eq!(passport_2.validate_all(), false);
 }
 
+fn test_passport_try_from_errors() {
+    let credential = build_credential("pid:087499704 hgt:74in ecl:grn iyr:2012 eyr:2030 byr:19x0
+    hcl:#623a2f".to_string());
+    assert_eq!(Passport::try_from(&credential).err(), Some(PassportError::InvalidNumber {
+        field: "byr".to_string(),
+        value: "19x0".to_string(),
+    }));
+
+    let credential_2 = build_credential("pid:087499704 hgt:74in ecl:grn iyr:2012 byr:1980
+    hcl:#623a2f".to_string());
+    assert_eq!(Passport::try_from(&credential_2).err(), Some(PassportError::MissingField("eyr".to_string())));
+
+    let credential_3 = build_credential("pid:087499704 hgt:cm ecl:grn iyr:2012 eyr:2030 byr:1980
+    hcl:#623a2f".to_string());
+    let passport_3 = Passport::try_from(&credential_3).unwrap();
+    assert!(!passport_3.validate_height());
+
+    let credential_block = "pid:087499704 hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980
+hcl:#623a2f
+
+pid:087499704 hgt:74in ecl:grn iyr:twenty eyr:2030 byr:1980
+hcl:#623a2f
+
+pid:087499704 hgt:in ecl:grn iyr:2012 eyr:2030 byr:1980
+hcl:#623a2f".to_string();
+    let credentials = build_credentials(credential_block);
+    assert_eq!(count_valid_v2(&credentials, &REQUIRED_FIELDS_PART_ONE.to_vec()), 1);
+}
+
 fn test_validate_passports_count() {
     let credential_block = "
 pid:087499704 hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980
@@ -295,3 +892,119 @@ pid:3556412378 byr:2007".to_string();
     let credentials_no_valid = build_credentials(credential_block_no_valid);
     assert_eq!(count_valid_v2(&credentials_no_valid, &REQUIRED_FIELDS_PART_ONE.to_vec()), 0);
 }
+
+
+fn test_normalize_credential() {
+    let credential = build_credential("pid:087499704 hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980
+    hcl:#623a2f cid:88".to_string());
+    assert_eq!(normalize_credential(&credential), Ok(NormalizedPassport {
+        byr: 1980,
+        iyr: 2012,
+        eyr: 2030,
+        hgt: Height::Inches(74),
+        hcl: (0x62, 0x3a, 0x2f),
+        ecl: "grn".to_string(),
+        pid: "087499704".to_string(),
+        cid: Some("88".to_string()),
+    }));
+    assert_eq!(Height::Inches(74).centimetres(), 187.96);
+    assert_eq!(Height::parse("cm"), None);
+
+    let credential_2 = build_credential("eyr:1972 cid:100
+    hcl:#18171d ecl:amb hgt:170 pid:186cm iyr:2018 byr:1926".to_string());
+    assert_eq!(normalize_credential(&credential_2), Err(vec![
+        "invalid eyr: \"1972\"".to_string(),
+        "invalid hgt: \"170\"".to_string(),
+        "invalid pid: \"186cm\"".to_string(),
+    ]));
+
+    let credential_3 = build_credential("eyr:2020 hgt:170cm".to_string());
+    assert_eq!(normalize_credential(&credential_3).unwrap_err().len(), 5);
+}
+
+fn test_write_passports() {
+    let records = read_credential_records("pid:087499704 hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980
+hcl:#623a2f
+
+hcl:dab227 iyr:2012
+ecl:brn hgt:182cm pid:021572410 eyr:2020 byr:1992 cid:277");
+    let (passports, rejects) = normalize_records(&records);
+    assert_eq!(passports.len(), 1);
+
+    let mut csv = Vec::new();
+    write_passports(&mut csv, &passports, ExportFormat::Csv).unwrap();
+    assert_eq!(String::from_utf8(csv).unwrap(), "byr,iyr,eyr,height_cm,hair_r,hair_g,hair_b,ecl,pid,cid
+1980,2012,2030,187.96,98,58,47,grn,087499704,
+");
+
+    let mut json = Vec::new();
+    write_passports(&mut json, &passports, ExportFormat::JsonLines).unwrap();
+    assert_eq!(String::from_utf8(json).unwrap(),
+        "{\"byr\":1980,\"iyr\":2012,\"eyr\":2030,\"height_cm\":187.96,\"hair_rgb\":[98,58,47],\"ecl\":\"grn\",\"pid\":\"087499704\",\"cid\":null}\n");
+
+    let mut key_value = Vec::new();
+    write_passports(&mut key_value, &passports, ExportFormat::KeyValue).unwrap();
+    let key_value = String::from_utf8(key_value).unwrap();
+    assert_eq!(key_value, "byr:1980 iyr:2012 eyr:2030 hgt:74in hcl:#623a2f ecl:grn pid:087499704\n\n");
+    let round_trip = read_credential_records(&key_value);
+    assert_eq!(normalize_records(&round_trip).0, passports);
+
+    let inch_records = read_credential_records("pid:087499704 hgt:66in ecl:grn iyr:2012 eyr:2030 byr:1980 hcl:#623a2f
+
+pid:087499705 hgt:73in ecl:grn iyr:2012 eyr:2030 byr:1980 hcl:#623a2f");
+    let (inch_passports, _) = normalize_records(&inch_records);
+    let mut csv = Vec::new();
+    write_passports(&mut csv, &inch_passports, ExportFormat::Csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    assert!(csv.contains("\n1980,2012,2030,167.64,98,58,47,grn,087499704,\n"));
+    assert!(csv.contains("\n1980,2012,2030,185.42,98,58,47,grn,087499705,\n"));
+    let mut json = Vec::new();
+    write_passports(&mut json, &inch_passports, ExportFormat::JsonLines).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains("\"height_cm\":167.64,"));
+    assert!(json.contains("\"height_cm\":185.42,"));
+
+    // Values with commas or quotes still come out as one CSV field each.
+    let awkward = NormalizedPassport { cid: Some("12,\"a\"".to_string()), ..normalize_records(&records).0.remove(0) };
+    let mut csv = Vec::new();
+    write_passports(&mut csv, &[awkward], ExportFormat::Csv).unwrap();
+    assert_eq!(String::from_utf8(csv).unwrap().lines().nth(1),
+        Some("1980,2012,2030,187.96,98,58,47,grn,087499704,\"12,\"\"a\"\"\""));
+
+    let mut rejected = Vec::new();
+    write_rejects(&mut rejected, &rejects).unwrap();
+    assert_eq!(String::from_utf8(rejected).unwrap(),
+        "{\"line\":4,\"fields\":{\"byr\":\"1992\",\"cid\":\"277\",\"ecl\":\"brn\",\"eyr\":\"2020\",\"hcl\":\"dab227\",\"hgt\":\"182cm\",\"iyr\":\"2012\",\"pid\":\"021572410\"},\"reasons\":[\"invalid hcl: \\\"dab227\\\"\"]}\n");
+
+    assert!("tsv".parse::<ExportFormat>().is_err());
+
+    // The duplicate byr explains why the record fails on its birth year.
+    let records = read_credential_records("byr:1890 byr:1980 iyr:2012 eyr:2030 hgt:74in hcl:#623a2f ecl:grn pid:087499704 foo:bar");
+    let (passports, rejects) = normalize_records(&records);
+    assert_eq!(passports.len(), 0);
+    assert_eq!(rejects[0].reasons, vec![
+        "duplicate key byr (kept \"1890\", ignored \"1980\")".to_string(),
+        "unknown field foo".to_string(),
+        "invalid byr: \"1890\"".to_string(),
+    ]);
+    let mut rejected = Vec::new();
+    write_rejects(&mut rejected, &rejects).unwrap();
+    assert!(String::from_utf8(rejected).unwrap().contains(
+        "\"reasons\":[\"duplicate key byr (kept \\\"1890\\\", ignored \\\"1980\\\")\",\"unknown field foo\",\"invalid byr: \\\"1890\\\"\"]"));
+}
+
+fn test_count_valid_parallel() {
+    let mut generated_block = Vec::new();
+    generate_records(&mut generated_block, 2000, 7).unwrap();
+    let credentials = build_credentials(String::from_utf8(generated_block.clone()).unwrap());
+    assert_eq!(credentials.len(), 2000);
+
+    let required_fields = REQUIRED_FIELDS_PART_ONE.to_vec();
+    let serial = count_valid_v2(&credentials, &required_fields);
+    assert!(serial > 0 && serial < 2000);
+    assert_eq!(count_valid_v2_parallel(&credentials, &required_fields), serial);
+
+    let block = String::from_utf8(generated_block).unwrap();
+    assert_eq!(count_valid_block(&block, &required_fields, false), serial);
+    assert_eq!(count_valid_block(&block, &required_fields, true), serial);
+}
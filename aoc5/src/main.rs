@@ -1,18 +1,52 @@
-use std::collections::HashMap;
-use std::fs::File;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader};
+use std::process;
 
 fn main() {
     test_seat();
+    test_seat_errors();
+    test_encode_seat();
+    test_custom_layout();
     test_largest_seat_id();
+    test_fill_plane();
+    test_missing_seat_ids();
+    test_occupancy_report();
+    test_render_seat_map();
+    test_render_svg();
 
+    let layout = AircraftLayout::standard();
     let passes = read_data("src/input.txt");
-    let largest_seat = largest_seat_id(&passes);
+    let largest_seat = largest_seat_id(&passes, &layout);
     println!("{}", largest_seat);
 
-    let filled_plane = fill_plane(&passes);
-    println!("{:?}", filled_plane);
+    let occupancy = PlaneOccupancy::board(&passes, &layout);
+    println!("your seat: {:?}", occupancy.missing_seat_ids());
 
+    let args: Vec<String> = env::args().collect();
+    if args.iter().any(|arg| arg == "--map") {
+        print!("{}", occupancy.render_text());
+    }
+    if args.iter().any(|arg| arg == "--report") {
+        print!("{}", occupancy.report());
+    }
+    if let Some(position) = args.iter().position(|arg| arg == "--svg") {
+        let path = match args.get(position + 1) {
+            Some(path) => path,
+            None => {
+                eprintln!("usage: {} [--map] [--report] [--svg <output>]", args[0]);
+                process::exit(2);
+            }
+        };
+        if let Err(e) = fs::write(path, occupancy.render_svg()) {
+            eprintln!("writing {}: {}", path, e);
+            process::exit(1);
+        }
+        println!("seat map written to {}", path);
+    }
 }
 
 fn read_data(filename: &str) -> Vec<String> {
@@ -24,140 +58,740 @@ fn read_data(filename: &str) -> Vec<String> {
     return passes;
 }
 
-struct Seat {
-    boarding_pass: Vec<String>,
-    row: Option<u8>,
-    column: Option<u8>,
-    seat_id: Option<u16>,
+#[derive(Debug, PartialEq)]
+enum BoardingPassError {
+    InvalidLength { length: usize, expected: usize },
+    InvalidCharacter { position: usize, character: char },
+    RowOutOfRange(u32),
+    ColumnOutOfRange(u32),
+    MissingRow(u32),
+    Aisle(u32),
+    UnknownSeatId(u32),
+    SeatIdOutOfRange(u32),
+    SeatIdOverflow { row: u32, column: u32 },
 }
 
-impl Seat {
-    pub fn new(boarding_pass: Vec<String>) -> Seat {
-        Seat {
-            boarding_pass: boarding_pass,
-            row: None,
-            column: None,
-            seat_id: None,
+impl fmt::Display for BoardingPassError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BoardingPassError::InvalidLength { length, expected } => {
+                write!(f, "boarding pass has {} letters, expected {}", length, expected)
+            }
+            BoardingPassError::InvalidCharacter { position, character } => {
+                write!(f, "unexpected {:?} at position {}", character, position)
+            }
+            BoardingPassError::RowOutOfRange(row) => write!(f, "row {} is out of range", row),
+            BoardingPassError::ColumnOutOfRange(column) => write!(f, "column {} is out of range", column),
+            BoardingPassError::MissingRow(row) => write!(f, "row {} does not exist on this aircraft", row),
+            BoardingPassError::Aisle(column) => write!(f, "column {} is an aisle", column),
+            BoardingPassError::UnknownSeatId(seat_id) => write!(f, "no seat has id {}", seat_id),
+            BoardingPassError::SeatIdOutOfRange(seat_id) => write!(f, "seat id {} is out of range", seat_id),
+            BoardingPassError::SeatIdOverflow { row, column } => {
+                write!(f, "seat id for row {} column {} does not fit in 32 bits", row, column)
+            }
         }
     }
+}
+
+impl std::error::Error for BoardingPassError {}
 
-    pub fn calculate_row(&mut self) -> u8 {
-        if self.row.is_some() {
-            return self.row.unwrap();
+/// Letters used for the lower and upper half when bisecting rows and columns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PassAlphabet {
+    row: (char, char),
+    column: (char, char),
+}
+
+impl PassAlphabet {
+    /// Rejects pairs that use the same letter for both halves, since such a
+    /// pass could not be read back.
+    pub fn new(row: (char, char), column: (char, char)) -> Result<PassAlphabet, String> {
+        for (name, (low, high)) in &[("row", row), ("column", column)] {
+            if low == high {
+                return Err(format!("{} letters must differ, both are {:?}", name, low));
+            }
         }
+        return Ok(PassAlphabet { row, column });
+    }
 
-        let mut bounds = &mut (u8::from(0), u8::from(127));
-        for half in self.boarding_pass[..7].iter() {
-            binary_reduce(&mut bounds, half == "F");
+    pub fn standard() -> PassAlphabet {
+        PassAlphabet {
+            row: ('F', 'B'),
+            column: ('L', 'R'),
         }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SeatIdFormula {
+    /// `row * multiplier + column`, which the puzzle uses with a multiplier of 8.
+    RowMajor(u32),
+    Custom(fn(u32, u32) -> u32),
+}
+
+/// Number of bisection letters needed to address `count` positions.
+fn letters_for(count: u32) -> usize {
+    if count <= 1 {
+        return 0;
+    }
+    return (32 - (count - 1).leading_zeros()) as usize;
+}
+
+struct AircraftLayout {
+    rows: u32,
+    columns: u32,
+    alphabet: PassAlphabet,
+    seat_id_formula: SeatIdFormula,
+    missing_rows: BTreeSet<u32>,
+    aisles: BTreeSet<u32>,
+}
+
+impl Default for AircraftLayout {
+    fn default() -> AircraftLayout {
+        return AircraftLayout::new(128, 8);
+    }
+}
 
-        let mut row = bounds.1;
-        if self.boarding_pass[6] == "F" {
-            row = bounds.0;
+impl AircraftLayout {
+    /// Seat ids default to `row * m + column` with `m` the smallest power of
+    /// two that fits `columns`, so an id is the pass read as a binary number.
+    /// A 10-column layout numbers rows 16 apart; use `with_seat_id_formula`
+    /// with `SeatIdFormula::RowMajor(columns)` for dense ids.
+    pub fn new(rows: u32, columns: u32) -> AircraftLayout {
+        AircraftLayout {
+            rows,
+            columns,
+            alphabet: PassAlphabet::standard(),
+            seat_id_formula: SeatIdFormula::RowMajor(columns.next_power_of_two()),
+            missing_rows: BTreeSet::new(),
+            aisles: BTreeSet::new(),
         }
+    }
+
+    /// The puzzle's aircraft: 128 rows of 8 seats, ids `row * 8 + column`.
+    pub fn standard() -> AircraftLayout {
+        return AircraftLayout::default();
+    }
+
+    pub fn with_alphabet(mut self, alphabet: PassAlphabet) -> AircraftLayout {
+        self.alphabet = alphabet;
+        return self;
+    }
+
+    pub fn with_seat_id_formula(mut self, seat_id_formula: SeatIdFormula) -> AircraftLayout {
+        self.seat_id_formula = seat_id_formula;
+        return self;
+    }
+
+    pub fn with_missing_rows(mut self, rows: &[u32]) -> AircraftLayout {
+        self.missing_rows.extend(rows);
+        return self;
+    }
+
+    pub fn with_aisles(mut self, columns: &[u32]) -> AircraftLayout {
+        self.aisles.extend(columns);
+        return self;
+    }
+
+    pub fn row_letters(&self) -> usize {
+        return letters_for(self.rows);
+    }
+
+    pub fn column_letters(&self) -> usize {
+        return letters_for(self.columns);
+    }
+
+    pub fn pass_length(&self) -> usize {
+        return self.row_letters() + self.column_letters();
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = u32> + '_ {
+        return (0..self.rows).filter(move |row| !self.missing_rows.contains(row));
+    }
 
-        self.row = Some(row);
-        return row
+    pub fn columns(&self) -> impl Iterator<Item = u32> + '_ {
+        return (0..self.columns).filter(move |column| !self.aisles.contains(column));
     }
 
-    pub fn calculate_column(&mut self) -> u8 {
-        if self.column.is_some() {
-            return self.column.unwrap();
+    /// `None` when a row-major id does not fit in a `u32`, which large
+    /// custom layouts can reach.
+    pub fn seat_id(&self, row: u32, column: u32) -> Option<u32> {
+        match self.seat_id_formula {
+            SeatIdFormula::RowMajor(multiplier) => row.checked_mul(multiplier)?.checked_add(column),
+            SeatIdFormula::Custom(formula) => Some(formula(row, column)),
         }
+    }
 
-        let mut bounds = &mut (u8::from(0), u8::from(7));
-        for half in self.boarding_pass[7..].iter() {
-            binary_reduce(&mut bounds, half == "L");
+    fn check_position(&self, row: u32, column: u32) -> Result<(), BoardingPassError> {
+        if row >= self.rows {
+            return Err(BoardingPassError::RowOutOfRange(row));
+        }
+        if column >= self.columns {
+            return Err(BoardingPassError::ColumnOutOfRange(column));
+        }
+        if self.missing_rows.contains(&row) {
+            return Err(BoardingPassError::MissingRow(row));
         }
+        if self.aisles.contains(&column) {
+            return Err(BoardingPassError::Aisle(column));
+        }
+        return Ok(());
+    }
 
-        let mut column = bounds.1;
-        if self.boarding_pass.last().unwrap() == "L" {
-            column = bounds.0;
+    /// Each half of the pass is a binary number: the lower letter is a 0 bit
+    /// and the upper letter a 1 bit.
+    pub fn decode(&self, pass: &str) -> Result<Seat, BoardingPassError> {
+        let length = pass.chars().count();
+        if length != self.pass_length() {
+            return Err(BoardingPassError::InvalidLength { length, expected: self.pass_length() });
         }
 
-        self.column = Some(column);
-        return column
+        let mut row = 0;
+        let mut column = 0;
+        for (position, character) in pass.chars().enumerate() {
+            let (target, (low, high)) = if position < self.row_letters() {
+                (&mut row, self.alphabet.row)
+            }
+            else {
+                (&mut column, self.alphabet.column)
+            };
+            let bit = if character == low {
+                0
+            }
+            else if character == high {
+                1
+            }
+            else {
+                return Err(BoardingPassError::InvalidCharacter { position, character });
+            };
+            *target = *target << 1 | bit;
+        }
+
+        self.check_position(row, column)?;
+        Ok(Seat {
+            boarding_pass: pass.to_string(),
+            row,
+            column,
+            seat_id: self.seat_id(row, column).ok_or(BoardingPassError::SeatIdOverflow { row, column })?,
+        })
     }
 
-    pub fn calculate_seat_id(&mut self) -> u16 {
-        if self.seat_id.is_some() {
-            return self.seat_id.unwrap();
-        }
+    pub fn encode(&self, row: u32, column: u32) -> Result<Seat, BoardingPassError> {
+        self.check_position(row, column)?;
 
-        let column = self.calculate_column() as u16;
-        let row = self.calculate_row() as u16;
+        let bits = |value: u32, letters: usize, (low, high): (char, char)| {
+            (0..letters).rev().map(move |bit| if value >> bit & 1 == 1 { high } else { low })
+        };
+        let boarding_pass = bits(row, self.row_letters(), self.alphabet.row)
+            .chain(bits(column, self.column_letters(), self.alphabet.column))
+            .collect();
 
-        let seat_id: u16 = (row * 8) + column;
+        Ok(Seat {
+            boarding_pass,
+            row,
+            column,
+            seat_id: self.seat_id(row, column).ok_or(BoardingPassError::SeatIdOverflow { row, column })?,
+        })
+    }
+
+    pub fn encode_seat_id(&self, seat_id: u32) -> Result<Seat, BoardingPassError> {
+        if let SeatIdFormula::RowMajor(multiplier) = self.seat_id_formula {
+            if multiplier > 0 && seat_id / multiplier >= self.rows {
+                return Err(BoardingPassError::SeatIdOutOfRange(seat_id));
+            }
+            if multiplier > 0 && seat_id % multiplier < self.columns {
+                return self.encode(seat_id / multiplier, seat_id % multiplier)
+                    .map_err(|_| BoardingPassError::UnknownSeatId(seat_id));
+            }
+            return Err(BoardingPassError::UnknownSeatId(seat_id));
+        }
 
-        self.seat_id = Some(seat_id);
-        return seat_id;
+        for row in self.rows() {
+            for column in self.columns() {
+                if self.seat_id(row, column) == Some(seat_id) {
+                    return self.encode(row, column);
+                }
+            }
+        }
+        return Err(BoardingPassError::UnknownSeatId(seat_id));
     }
 }
 
-fn binary_reduce(bounds: &mut (u8, u8), take_lower: bool) -> &mut (u8, u8) {
-    if take_lower {
-        let old_bounds = (bounds.0, bounds.1);
-        bounds.1 = f32::round(old_bounds.1 as f32 - (old_bounds.1 as f32 - old_bounds.0 as f32 + 1.0) / 2.0) as u8;
+struct Seat {
+    boarding_pass: String,
+    row: u32,
+    column: u32,
+    seat_id: u32,
+}
+
+impl Seat {
+    /// Decodes a pass for the puzzle's standard aircraft.
+    pub fn new(boarding_pass: &str) -> Result<Seat, BoardingPassError> {
+        return AircraftLayout::default().decode(boarding_pass);
+    }
+
+    pub fn from_seat_id(seat_id: u32) -> Result<Seat, BoardingPassError> {
+        return AircraftLayout::default().encode_seat_id(seat_id);
+    }
+
+    pub fn boarding_pass(&self) -> &str {
+        return &self.boarding_pass;
+    }
+
+    pub fn row(&self) -> u32 {
+        return self.row;
+    }
+
+    pub fn column(&self) -> u32 {
+        return self.column;
     }
-    else {
-        let old_bounds = (bounds.0, bounds.1);
-        bounds.0 = f32::round(old_bounds.0 as f32 + (old_bounds.1 as f32 - old_bounds.0 as f32 + 1.0) / 2.0) as u8;
+
+    pub fn seat_id(&self) -> u32 {
+        return self.seat_id;
     }
-    return bounds;
 }
 
-fn largest_seat_id(passes: &Vec<String>) -> u16 {
+fn largest_seat_id(passes: &Vec<String>, layout: &AircraftLayout) -> u32 {
     let mut max_seat_id = 0;
     for pass in passes {
-        let mut seat = Seat::new(pass.chars().map(|c| c.to_string()).collect());
-        if seat.calculate_seat_id() > max_seat_id {
-            max_seat_id = seat.calculate_seat_id();
+        if let Ok(seat) = layout.decode(pass) {
+            if seat.seat_id() > max_seat_id {
+                max_seat_id = seat.seat_id();
+            }
         }
     }
 
     return max_seat_id;
 }
 
-fn initialize_empty_plane() -> HashMap<u8, HashMap<u8, u16>> {
+fn initialize_empty_plane(layout: &AircraftLayout) -> HashMap<u32, HashMap<u32, u32>> {
     let mut plane = HashMap::new();
-    for row in 0..127 {
+    for row in layout.rows() {
         plane.entry(row).or_insert(HashMap::new());
     }
     return plane;
 }
 
-fn fill_plane(passes: &Vec<String>) -> HashMap<u8, HashMap<u8, u16>> {
-    let mut plane = initialize_empty_plane();
+fn fill_plane(passes: &Vec<String>, layout: &AircraftLayout) -> HashMap<u32, HashMap<u32, u32>> {
+    let mut plane = initialize_empty_plane(layout);
     for pass in passes {
-        let mut seat = Seat::new(pass.chars().map(|c| c.to_string()).collect());
-        seat.calculate_seat_id();
-        let column = plane.get_mut(&seat.row.unwrap()).unwrap();
-        column.entry(seat.column.unwrap()).or_insert(seat.seat_id.unwrap());
+        if let Ok(seat) = layout.decode(pass) {
+            let column = plane.get_mut(&seat.row()).unwrap();
+            column.entry(seat.column()).or_insert(seat.seat_id());
+        }
     }
 
     return plane;
 }
 
+#[derive(Debug, PartialEq)]
+struct RowOccupancy {
+    row: u32,
+    occupied: usize,
+    capacity: usize,
+}
+
+/// A run of consecutive empty seats in boarding order (row by row, left to right).
+#[derive(Debug, PartialEq)]
+struct EmptyBlock {
+    first: (u32, u32),
+    last: (u32, u32),
+    seats: usize,
+}
+
+#[derive(Debug, PartialEq)]
+struct DuplicateSeat {
+    row: u32,
+    column: u32,
+    seat_id: u32,
+    passes: Vec<String>,
+}
+
+struct PlaneOccupancy<'a> {
+    layout: &'a AircraftLayout,
+    seats: BTreeMap<(u32, u32), Vec<String>>,
+    invalid: Vec<(String, BoardingPassError)>,
+}
+
+impl<'a> PlaneOccupancy<'a> {
+    pub fn board(passes: &[String], layout: &'a AircraftLayout) -> PlaneOccupancy<'a> {
+        let mut seats: BTreeMap<(u32, u32), Vec<String>> = BTreeMap::new();
+        let mut invalid = Vec::new();
+        for pass in passes {
+            match layout.decode(pass) {
+                Ok(seat) => seats.entry((seat.row(), seat.column())).or_default().push(pass.to_string()),
+                Err(e) => invalid.push((pass.to_string(), e)),
+            }
+        }
+        return PlaneOccupancy { layout, seats, invalid };
+    }
+
+    pub fn is_occupied(&self, row: u32, column: u32) -> bool {
+        return self.seats.contains_key(&(row, column));
+    }
+
+    pub fn invalid_passes(&self) -> &[(String, BoardingPassError)] {
+        return &self.invalid;
+    }
+
+    /// Empty seats whose neighbouring seats by id are both taken, which is
+    /// where the missing passenger must be sitting. Aisles and missing rows
+    /// are skipped over, so the neighbours are the nearest real seats.
+    pub fn missing_seat_ids(&self) -> Vec<u32> {
+        let mut seat_ids: Vec<(u32, bool)> = Vec::new();
+        for row in self.layout.rows() {
+            for column in self.layout.columns() {
+                if let Some(seat_id) = self.layout.seat_id(row, column) {
+                    seat_ids.push((seat_id, self.is_occupied(row, column)));
+                }
+            }
+        }
+        seat_ids.sort();
+
+        return seat_ids.windows(3)
+            .filter(|seats| seats[0].1 && !seats[1].1 && seats[2].1)
+            .map(|seats| seats[1].0)
+            .collect();
+    }
+
+    pub fn row_occupancy(&self) -> Vec<RowOccupancy> {
+        let capacity = self.layout.columns().count();
+        return self.layout.rows().map(|row| RowOccupancy {
+            row,
+            occupied: self.layout.columns().filter(|column| self.is_occupied(row, *column)).count(),
+            capacity,
+        }).collect();
+    }
+
+    pub fn empty_blocks(&self) -> Vec<EmptyBlock> {
+        let mut blocks = Vec::new();
+        let mut current: Option<EmptyBlock> = None;
+        for row in self.layout.rows() {
+            for column in self.layout.columns() {
+                if self.is_occupied(row, column) {
+                    if let Some(block) = current.take() {
+                        blocks.push(block);
+                    }
+                    continue;
+                }
+                match current.as_mut() {
+                    Some(block) => {
+                        block.last = (row, column);
+                        block.seats += 1;
+                    }
+                    None => current = Some(EmptyBlock { first: (row, column), last: (row, column), seats: 1 }),
+                }
+            }
+        }
+        if let Some(block) = current {
+            blocks.push(block);
+        }
+        return blocks;
+    }
+
+    pub fn duplicates(&self) -> Vec<DuplicateSeat> {
+        return self.seats.iter()
+            .filter(|(_, passes)| passes.len() > 1)
+            .map(|((row, column), passes)| DuplicateSeat {
+                row: *row,
+                column: *column,
+                seat_id: self.layout.seat_id(*row, *column).expect("boarded seats were decoded with an id"),
+                passes: passes.clone(),
+            })
+            .collect();
+    }
+
+    pub fn report(&self) -> OccupancyReport {
+        OccupancyReport {
+            passengers: self.seats.values().map(|passes| passes.len()).sum(),
+            rows: self.row_occupancy(),
+            empty_blocks: self.empty_blocks(),
+            duplicates: self.duplicates(),
+            missing_seat_ids: self.missing_seat_ids(),
+            invalid: self.invalid.iter().map(|(pass, e)| format!("{}: {}", pass, e)).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SeatState {
+    Empty,
+    Occupied,
+    Duplicate,
+    Candidate,
+    Aisle,
+}
+
+impl SeatState {
+    pub fn marker(&self) -> char {
+        match self {
+            SeatState::Empty => '.',
+            SeatState::Occupied => '#',
+            SeatState::Duplicate => 'D',
+            SeatState::Candidate => '?',
+            SeatState::Aisle => ' ',
+        }
+    }
+
+    pub fn fill(&self) -> &'static str {
+        match self {
+            SeatState::Empty => "#eeeeee",
+            SeatState::Occupied => "#4a7f5a",
+            SeatState::Duplicate => "#c8323c",
+            SeatState::Candidate => "#f5c518",
+            SeatState::Aisle => "none",
+        }
+    }
+}
+
+const SVG_SEAT_SIZE: u32 = 16;
+const SVG_SEAT_GAP: u32 = 4;
+const SVG_LABEL_WIDTH: u32 = 36;
+
+impl<'a> PlaneOccupancy<'a> {
+    pub fn seat_state(&self, row: u32, column: u32, candidates: &HashSet<u32>) -> SeatState {
+        if self.layout.aisles.contains(&column) {
+            return SeatState::Aisle;
+        }
+        match self.seats.get(&(row, column)) {
+            Some(passes) if passes.len() > 1 => SeatState::Duplicate,
+            Some(_) => SeatState::Occupied,
+            None if self.layout.seat_id(row, column).is_some_and(|seat_id| candidates.contains(&seat_id)) => SeatState::Candidate,
+            None => SeatState::Empty,
+        }
+    }
+
+    /// Every existing row with the state of each column, aisles included so
+    /// renderers can leave a gap for them.
+    pub fn seat_grid(&self) -> Vec<(u32, Vec<SeatState>)> {
+        let candidates: HashSet<u32> = self.missing_seat_ids().into_iter().collect();
+        return self.layout.rows().map(|row| {
+            (row, (0..self.layout.columns).map(|column| self.seat_state(row, column, &candidates)).collect())
+        }).collect();
+    }
+
+    pub fn render_text(&self) -> String {
+        let mut map = String::new();
+        let header: String = (0..self.layout.columns).map(|column| {
+            if self.layout.aisles.contains(&column) {
+                ' '
+            }
+            else {
+                std::char::from_digit(column % 10, 10).unwrap()
+            }
+        }).collect();
+        writeln!(map, "    {}", header).unwrap();
+
+        let mut previous_row = None;
+        for (row, states) in self.seat_grid() {
+            if previous_row.is_some_and(|previous| previous + 1 != row) {
+                writeln!(map, "    {}", "~".repeat(self.layout.columns as usize)).unwrap();
+            }
+            let seats: String = states.iter().map(|state| state.marker()).collect();
+            writeln!(map, "{:>3} {}", row, seats).unwrap();
+            previous_row = Some(row);
+        }
+        writeln!(map, "# occupied  . empty  D duplicate pass  ? candidate seat").unwrap();
+        return map;
+    }
+
+    pub fn render_svg(&self) -> String {
+        let pitch = SVG_SEAT_SIZE + SVG_SEAT_GAP;
+        let grid = self.seat_grid();
+        let width = SVG_LABEL_WIDTH + self.layout.columns * pitch;
+        let height = grid.len() as u32 * pitch + SVG_SEAT_GAP;
+
+        let mut svg = String::new();
+        writeln!(svg, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">",
+            width, height, width, height).unwrap();
+        writeln!(svg, "<rect width=\"100%\" height=\"100%\" fill=\"white\"/>").unwrap();
+        for (index, (row, states)) in grid.iter().enumerate() {
+            let y = SVG_SEAT_GAP + index as u32 * pitch;
+            writeln!(svg, "<text x=\"{}\" y=\"{}\" font-family=\"monospace\" font-size=\"12\" text-anchor=\"end\">{}</text>",
+                SVG_LABEL_WIDTH - SVG_SEAT_GAP * 2, y + SVG_SEAT_SIZE - 3, row).unwrap();
+            for (column, state) in states.iter().enumerate() {
+                if *state == SeatState::Aisle {
+                    continue;
+                }
+                let x = SVG_LABEL_WIDTH + column as u32 * pitch;
+                let stroke = if *state == SeatState::Candidate { "#000000" } else { "#999999" };
+                let title = match self.layout.seat_id(*row, column as u32) {
+                    Some(seat_id) => format!("seat {}", seat_id),
+                    None => format!("row {} column {}", row, column),
+                };
+                writeln!(svg, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"3\" fill=\"{}\" stroke=\"{}\"><title>{}</title></rect>",
+                    x, y, SVG_SEAT_SIZE, SVG_SEAT_SIZE, state.fill(), stroke, title).unwrap();
+            }
+        }
+        writeln!(svg, "</svg>").unwrap();
+        return svg;
+    }
+}
+
+struct OccupancyReport {
+    passengers: usize,
+    rows: Vec<RowOccupancy>,
+    empty_blocks: Vec<EmptyBlock>,
+    duplicates: Vec<DuplicateSeat>,
+    missing_seat_ids: Vec<u32>,
+    invalid: Vec<String>,
+}
+
+impl fmt::Display for OccupancyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let capacity: usize = self.rows.iter().map(|row| row.capacity).sum();
+        writeln!(f, "passengers: {} / {} seats", self.passengers, capacity)?;
+        writeln!(f, "candidate seats: {:?}", self.missing_seat_ids)?;
+
+        writeln!(f, "rows not full:")?;
+        for row in self.rows.iter().filter(|row| row.occupied < row.capacity) {
+            writeln!(f, "  row {:>3}: {}/{}", row.row, row.occupied, row.capacity)?;
+        }
+
+        writeln!(f, "empty blocks:")?;
+        for block in &self.empty_blocks {
+            writeln!(f, "  {:?} to {:?}: {} seats", block.first, block.last, block.seats)?;
+        }
+
+        if !self.duplicates.is_empty() {
+            writeln!(f, "duplicate passes:")?;
+            for duplicate in &self.duplicates {
+                writeln!(f, "  seat {} (row {}, column {}): {}",
+                    duplicate.seat_id, duplicate.row, duplicate.column, duplicate.passes.join(", "))?;
+            }
+        }
+        if !self.invalid.is_empty() {
+            writeln!(f, "invalid passes:")?;
+            for invalid in &self.invalid {
+                writeln!(f, "  {}", invalid)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
 fn test_seat() {
-    let mut seat = Seat::new("FBFBBFFRLR".chars().map(|c| c.to_string()).collect());
-    assert_eq!(seat.calculate_row(), 44);
-    assert_eq!(seat.calculate_column(), 5);
-    assert_eq!(seat.calculate_seat_id(), 357);
+    let seat = Seat::new("FBFBBFFRLR").unwrap();
+    assert_eq!(seat.row(), 44);
+    assert_eq!(seat.column(), 5);
+    assert_eq!(seat.seat_id(), 357);
+
+    let seat = Seat::new("BFFFBBFRRR").unwrap();
+    assert_eq!(seat.row(), 70);
+    assert_eq!(seat.column(), 7);
+    assert_eq!(seat.seat_id(), 567);
+
+    let seat = Seat::new("FFFBBBFRRR").unwrap();
+    assert_eq!(seat.row(), 14);
+    assert_eq!(seat.column(), 7);
+    assert_eq!(seat.seat_id(), 119);
+
+    let seat = Seat::new("BBFFBBFRLL").unwrap();
+    assert_eq!(seat.row(), 102);
+    assert_eq!(seat.column(), 4);
+    assert_eq!(seat.seat_id(), 820);
+}
+
+fn test_seat_errors() {
+    let layout = AircraftLayout::standard();
+    assert_eq!(layout.decode("FBFBBFFRL").err(), Some(BoardingPassError::InvalidLength {
+        length: 9,
+        expected: 10,
+    }));
+    assert_eq!(layout.decode("FBFBBFFRLRR").err(), Some(BoardingPassError::InvalidLength {
+        length: 11,
+        expected: 10,
+    }));
+    assert_eq!(layout.decode("FBFBBFFRXR").err(), Some(BoardingPassError::InvalidCharacter {
+        position: 8,
+        character: 'X',
+    }));
+    assert_eq!(layout.decode("FBFBBFLRLR").err(), Some(BoardingPassError::InvalidCharacter {
+        position: 6,
+        character: 'L',
+    }));
+    assert_eq!(layout.decode("FBFBBFFRLB").err(), Some(BoardingPassError::InvalidCharacter {
+        position: 9,
+        character: 'B',
+    }));
+    assert_eq!(layout.decode("BBBBBBBRRR").unwrap().seat_id(), 1023);
+    assert_eq!(layout.decode("FFFFFFFLLL").unwrap().seat_id(), 0);
+
+    assert_eq!(Seat::new("FBFBBFFRLR").unwrap().seat_id(), 357);
+    assert_eq!(Seat::new("FBFBBFFRL").err(), Some(BoardingPassError::InvalidLength {
+        length: 9,
+        expected: 10,
+    }));
+}
+
+fn test_encode_seat() {
+    let layout = AircraftLayout::standard();
+    assert_eq!(layout.encode_seat_id(357).unwrap().boarding_pass(), "FBFBBFFRLR");
+    assert_eq!(layout.encode(102, 4).unwrap().boarding_pass(), "BBFFBBFRLL");
+    assert_eq!(layout.encode_seat_id(1024).err(), Some(BoardingPassError::SeatIdOutOfRange(1024)));
+    assert_eq!(layout.encode(128, 0).err(), Some(BoardingPassError::RowOutOfRange(128)));
+    assert_eq!(layout.encode(0, 8).err(), Some(BoardingPassError::ColumnOutOfRange(8)));
+
+    for seat_id in 0..1024 {
+        let seat = layout.encode_seat_id(seat_id).unwrap();
+        assert_eq!(layout.decode(seat.boarding_pass()).unwrap().seat_id(), seat_id);
+    }
+
+    assert_eq!(Seat::from_seat_id(357).unwrap().boarding_pass(), "FBFBBFFRLR");
+    assert_eq!(Seat::from_seat_id(1024).err(), Some(BoardingPassError::SeatIdOutOfRange(1024)));
+    for seat_id in 0..1024 {
+        let seat = Seat::from_seat_id(seat_id).unwrap();
+        assert_eq!(Seat::new(seat.boarding_pass()).unwrap().seat_id(), seat_id);
+    }
+}
+
+fn test_custom_layout() {
+    let ten_across = AircraftLayout::new(4, 10);
+    assert_eq!(ten_across.seat_id(1, 0), Some(16));
+    let dense = AircraftLayout::new(4, 10).with_seat_id_formula(SeatIdFormula::RowMajor(10));
+    assert_eq!(dense.seat_id(1, 0), Some(10));
+
+    // 30 rows with no row 13, seven seats across with the aisle in column 3.
+    let layout = AircraftLayout::new(30, 7)
+        .with_alphabet(PassAlphabet::new(('A', 'Z'), ('0', '1')).unwrap())
+        .with_seat_id_formula(SeatIdFormula::Custom(|row, column| (row + 1) * 100 + column))
+        .with_missing_rows(&[13])
+        .with_aisles(&[3]);
+    assert_eq!(layout.pass_length(), 8);
+    assert_eq!(layout.rows().count(), 29);
+    assert_eq!(layout.columns().collect::<Vec<u32>>(), vec![0, 1, 2, 4, 5, 6]);
+
+    let seat = layout.decode("AZAZZ101").unwrap();
+    assert_eq!((seat.row(), seat.column(), seat.seat_id()), (11, 5, 1205));
+    assert_eq!(layout.encode(11, 5).unwrap().boarding_pass(), "AZAZZ101");
+    assert_eq!(layout.encode_seat_id(1205).unwrap().boarding_pass(), "AZAZZ101");
+
+    assert_eq!(layout.decode("AZAZZ011").err(), Some(BoardingPassError::Aisle(3)));
+    assert_eq!(layout.decode("AZAZZ111").err(), Some(BoardingPassError::ColumnOutOfRange(7)));
+    assert_eq!(layout.decode("AZZAZ000").err(), Some(BoardingPassError::MissingRow(13)));
+    assert_eq!(layout.decode("ZZZZZ000").err(), Some(BoardingPassError::RowOutOfRange(31)));
+    assert_eq!(layout.decode("FBFBBFFRLR").err(), Some(BoardingPassError::InvalidLength {
+        length: 10,
+        expected: 8,
+    }));
+    assert_eq!(layout.encode_seat_id(1403).err(), Some(BoardingPassError::UnknownSeatId(1403)));
 
-    let mut seat = Seat::new("BFFFBBFRRR".chars().map(|c| c.to_string()).collect());
-    assert_eq!(seat.calculate_row(), 70);
-    assert_eq!(seat.calculate_column(), 7);
-    assert_eq!(seat.calculate_seat_id(), 567);
+    assert_eq!(PassAlphabet::new(('F', 'F'), ('L', 'R')), Err("row letters must differ, both are 'F'".to_string()));
+    assert_eq!(PassAlphabet::new(('F', 'B'), ('R', 'R')), Err("column letters must differ, both are 'R'".to_string()));
 
-    let mut seat = Seat::new("FFFBBBFRRR".chars().map(|c| c.to_string()).collect());
-    assert_eq!(seat.calculate_row(), 14);
-    assert_eq!(seat.calculate_column(), 7);
-    assert_eq!(seat.calculate_seat_id(), 119);
+    // Row 2 with a huge multiplier has no id that fits in a u32.
+    let wide = AircraftLayout::new(4, 2).with_seat_id_formula(SeatIdFormula::RowMajor(u32::MAX / 2 + 1));
+    assert_eq!(wide.seat_id(1, 1), Some(u32::MAX / 2 + 2));
+    assert_eq!(wide.seat_id(2, 0), None);
+    assert_eq!(wide.encode(2, 0).err(), Some(BoardingPassError::SeatIdOverflow { row: 2, column: 0 }));
+    assert_eq!(wide.decode("BFL").err(), Some(BoardingPassError::SeatIdOverflow { row: 2, column: 0 }));
+    let occupancy = PlaneOccupancy::board(&["FFR".to_string(), "BBL".to_string()], &wide);
+    assert_eq!(occupancy.invalid_passes().len(), 1);
+    assert!(occupancy.render_svg().contains("<title>row 3 column 1</title>"));
 
-    let mut seat = Seat::new("BBFFBBFRLL".chars().map(|c| c.to_string()).collect());
-    assert_eq!(seat.calculate_row(), 102);
-    assert_eq!(seat.calculate_column(), 4);
-    assert_eq!(seat.calculate_seat_id(), 820);
+    let single_aisle = AircraftLayout::new(40, 6);
+    assert_eq!(single_aisle.pass_length(), 9);
+    assert_eq!(single_aisle.encode(39, 5).unwrap().seat_id(), 39 * 8 + 5);
 }
 
 fn test_largest_seat_id() {
@@ -166,5 +800,97 @@ fn test_largest_seat_id() {
         "BFFFBBFRRR".to_string(),
         "FFFBBBFRRR".to_string(),
         "BBFFBBFRLL".to_string(),
-    ]), 820)
-}
\ No newline at end of file
+        "BBFFBBFRLX".to_string(),
+    ], &AircraftLayout::standard()), 820)
+}
+
+fn test_fill_plane() {
+    let layout = AircraftLayout::standard();
+    let plane = fill_plane(&vec![
+        "BBBBBBBRRR".to_string(),
+        "FBFBBFFRLR".to_string(),
+    ], &layout);
+    assert_eq!(plane.len(), 128);
+    assert_eq!(plane[&127].get(&7), Some(&1023));
+    assert_eq!(plane[&44].get(&5), Some(&357));
+}
+
+fn test_missing_seat_ids() {
+    let layout = AircraftLayout::standard();
+    let passes: Vec<String> = (40..60)
+        .filter(|seat_id| *seat_id != 50)
+        .map(|seat_id| layout.encode_seat_id(seat_id).unwrap().boarding_pass().to_string())
+        .collect();
+    let occupancy = PlaneOccupancy::board(&passes, &layout);
+    assert_eq!(occupancy.missing_seat_ids(), vec![50]);
+
+    let mut gaps = passes.clone();
+    gaps.retain(|pass| layout.decode(pass).unwrap().seat_id() != 45);
+    gaps.retain(|pass| layout.decode(pass).unwrap().seat_id() != 46);
+    let occupancy = PlaneOccupancy::board(&gaps, &layout);
+    assert_eq!(occupancy.missing_seat_ids(), vec![50]);
+}
+
+fn test_occupancy_report() {
+    let layout = AircraftLayout::new(3, 4).with_aisles(&[1]);
+    let pass = |row, column| layout.encode(row, column).unwrap().boarding_pass().to_string();
+    let passes = vec![
+        pass(0, 0), pass(0, 2), pass(0, 3),
+        pass(1, 0), pass(1, 3), pass(1, 3),
+        "FFLR".to_string(),
+        "FF".to_string(),
+    ];
+    let occupancy = PlaneOccupancy::board(&passes, &layout);
+
+    assert_eq!(occupancy.row_occupancy(), vec![
+        RowOccupancy { row: 0, occupied: 3, capacity: 3 },
+        RowOccupancy { row: 1, occupied: 2, capacity: 3 },
+        RowOccupancy { row: 2, occupied: 0, capacity: 3 },
+    ]);
+    assert_eq!(occupancy.empty_blocks(), vec![
+        EmptyBlock { first: (1, 2), last: (1, 2), seats: 1 },
+        EmptyBlock { first: (2, 0), last: (2, 3), seats: 3 },
+    ]);
+    assert_eq!(occupancy.duplicates(), vec![DuplicateSeat {
+        row: 1,
+        column: 3,
+        seat_id: 7,
+        passes: vec!["FBRR".to_string(), "FBRR".to_string()],
+    }]);
+    assert_eq!(occupancy.missing_seat_ids(), vec![6]);
+    assert_eq!(occupancy.invalid_passes().len(), 2);
+
+    let report = occupancy.report().to_string();
+    assert!(report.starts_with("passengers: 6 / 9 seats\ncandidate seats: [6]\n"));
+    assert!(report.contains("  seat 7 (row 1, column 3): FBRR, FBRR\n"));
+    assert!(report.contains("  FFLR: column 1 is an aisle\n"));
+}
+
+fn test_render_seat_map() {
+    let layout = AircraftLayout::new(4, 4).with_aisles(&[1]).with_missing_rows(&[2]);
+    let pass = |row, column| layout.encode(row, column).unwrap().boarding_pass().to_string();
+    let passes = vec![
+        pass(0, 0), pass(0, 3),
+        pass(1, 0), pass(1, 2), pass(1, 2), pass(1, 3),
+    ];
+    let occupancy = PlaneOccupancy::board(&passes, &layout);
+    assert_eq!(occupancy.render_text(), "    0 23
+  0 # ?#
+  1 # D#
+    ~~~~
+  3 . ..
+# occupied  . empty  D duplicate pass  ? candidate seat
+");
+}
+
+fn test_render_svg() {
+    let layout = AircraftLayout::new(2, 2);
+    let pass = |row, column| layout.encode(row, column).unwrap().boarding_pass().to_string();
+    let occupancy = PlaneOccupancy::board(&[pass(0, 0), pass(1, 0)], &layout);
+    let svg = occupancy.render_svg();
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"76\" height=\"44\""));
+    assert!(svg.trim_end().ends_with("</svg>"));
+    assert_eq!(svg.matches("<rect x=").count(), 4);
+    assert_eq!(svg.matches("fill=\"#f5c518\"").count(), 1);
+    assert!(svg.contains("<title>seat 1</title>"));
+}
@@ -1,15 +1,40 @@
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
 use std::fs::File;
 use std::io::{Read};
+use std::process;
+use serde::Serialize;
 
 fn main() {
     test_group_questions_count();
     test_group_questions_count_v2();
     test_sum_of_group_answers();
+    test_question_set();
+    test_group_queries();
+    test_survey_report();
+    test_survey_report_output();
+    test_malformed_groups();
 
     let answers = read_data("src/input.txt");
-    println!("version 1 count {}", sum_of_group_answers(&answers, false));
-    println!("version 2 count {}", sum_of_group_answers(&answers, true));
+    for invalid in parse_groups_checked(&answers).1 {
+        eprintln!("line {}: ignoring {:?}", invalid.line, invalid.character);
+    }
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "report" {
+        let report = SurveyReport::from_groups(&parse_groups(&answers));
+        match args.get(2).map(|format| format.as_str()) {
+            Some("json") => println!("{}", report.to_json()),
+            Some("table") | None => print!("{}", report.to_table()),
+            Some(format) => {
+                eprintln!("unknown report format {:?}, expected table or json", format);
+                process::exit(2);
+            }
+        }
+        return;
+    }
+    println!("version 1 count {}", sum_of_group_answers(&answers, GroupQuery::Union));
+    println!("version 2 count {}", sum_of_group_answers(&answers, GroupQuery::Intersection));
 }
 
 
@@ -22,57 +47,362 @@ fn read_data(filename: &str) -> String {
     return answer_block;
 }
 
-fn sum_of_group_answers(group_answer_blocks: &String, version_2: bool) -> usize {
-    let mut sum = 0;
+/// The questions `a` to `z` as bits 0 to 25 of a single word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct QuestionSet(u32);
+
+impl QuestionSet {
+    pub const ALL: QuestionSet = QuestionSet((1 << 26) - 1);
 
-    for record in group_answer_blocks.split("\n\n"){
-        if version_2 {
-            sum += group_questions_count_v2(record.to_string());
+    pub fn empty() -> QuestionSet {
+        return QuestionSet(0);
+    }
+
+    /// Builds the set from one person's answers, ignoring anything that is
+    /// not a question letter.
+    pub fn from_answers(answers: &str) -> QuestionSet {
+        let mut set = QuestionSet::empty();
+        for question in answers.chars() {
+            set.insert(question);
         }
-        else {
-            sum += group_questions_count(record.to_string());
+        return set;
+    }
+
+    fn bit(question: char) -> Option<u32> {
+        if question.is_ascii_lowercase() {
+            return Some(1 << (question as u32 - 'a' as u32));
         }
+        return None;
     }
 
-    return sum;
+    /// Returns false if `question` is not one of `a` to `z`.
+    pub fn insert(&mut self, question: char) -> bool {
+        match QuestionSet::bit(question) {
+            Some(bit) => {
+                self.0 |= bit;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, question: char) -> bool {
+        return QuestionSet::bit(question).is_some_and(|bit| self.0 & bit != 0);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.0.count_ones() as usize;
+    }
 
+    pub fn is_empty(&self) -> bool {
+        return self.0 == 0;
+    }
+
+    pub fn union(self, other: QuestionSet) -> QuestionSet {
+        return QuestionSet(self.0 | other.0);
+    }
+
+    pub fn intersection(self, other: QuestionSet) -> QuestionSet {
+        return QuestionSet(self.0 & other.0);
+    }
+
+    pub fn symmetric_difference(self, other: QuestionSet) -> QuestionSet {
+        return QuestionSet(self.0 ^ other.0);
+    }
+
+    pub fn questions(self) -> impl Iterator<Item = char> {
+        return (b'a'..=b'z').map(char::from).filter(move |question| self.contains(*question));
+    }
 }
 
-fn group_questions_count(group_answers: String) -> usize {
-    let mut question_set = HashSet::new();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GroupQuery {
+    Union,
+    Intersection,
+    /// Questions answered by an odd number of people.
+    SymmetricDifference,
+    AtLeast(usize),
+    ExactlyOne,
+}
+
+struct Group {
+    people: Vec<QuestionSet>,
+}
+
+impl Group {
+    /// Treats every non-blank line as one person; blank lines are skipped.
+    pub fn parse(group_answers: &str) -> Group {
+        Group {
+            people: group_answers.lines()
+                .map(str::trim)
+                .filter(|answers| !answers.is_empty())
+                .map(QuestionSet::from_answers)
+                .collect(),
+        }
+    }
+
+    pub fn union(&self) -> QuestionSet {
+        return self.people.iter().fold(QuestionSet::empty(), |acc, person| acc.union(*person));
+    }
 
-    for person_answer in group_answers.lines() {
-        for question in person_answer.chars() {
-            question_set.insert(question);
+    /// Questions everyone answered; empty for a group with nobody in it.
+    pub fn intersection(&self) -> QuestionSet {
+        if self.people.is_empty() {
+            return QuestionSet::empty();
         }
+        return self.people.iter().fold(QuestionSet::ALL, |acc, person| acc.intersection(*person));
+    }
+
+    pub fn symmetric_difference(&self) -> QuestionSet {
+        return self.people.iter().fold(QuestionSet::empty(), |acc, person| acc.symmetric_difference(*person));
     }
 
-    return question_set.len()
+    /// How many people answered each question, indexed from `a`.
+    pub fn question_counts(&self) -> [usize; 26] {
+        let mut counts = [0; 26];
+        for person in &self.people {
+            for (index, count) in counts.iter_mut().enumerate() {
+                *count += (person.0 >> index & 1) as usize;
+            }
+        }
+        return counts;
+    }
+
+    fn answered_by(&self, keep: impl Fn(usize) -> bool) -> QuestionSet {
+        let mut set = QuestionSet::empty();
+        for (index, count) in self.question_counts().iter().enumerate() {
+            if keep(*count) {
+                set.0 |= 1 << index;
+            }
+        }
+        return set;
+    }
+
+    pub fn answered_by_at_least(&self, people: usize) -> QuestionSet {
+        return self.answered_by(|count| count > 0 && count >= people);
+    }
+
+    pub fn answered_by_exactly_one(&self) -> QuestionSet {
+        return self.answered_by(|count| count == 1);
+    }
+
+    pub fn query(&self, query: GroupQuery) -> QuestionSet {
+        match query {
+            GroupQuery::Union => self.union(),
+            GroupQuery::Intersection => self.intersection(),
+            GroupQuery::SymmetricDifference => self.symmetric_difference(),
+            GroupQuery::AtLeast(people) => self.answered_by_at_least(people),
+            GroupQuery::ExactlyOne => self.answered_by_exactly_one(),
+        }
+    }
 }
 
-fn group_questions_count_v2(group_answers: String) -> usize {
-    let mut question_set = HashSet::new();
+#[derive(Debug, PartialEq)]
+struct InvalidAnswer {
+    line: usize,
+    character: char,
+}
 
-    let groups = group_answers.lines().collect::<Vec<&str>>();
+/// Splits the survey into groups with these rules:
+///
+/// * groups are separated by one or more lines that are blank once trimmed,
+///   so CRLF endings, trailing whitespace and runs of blank lines all work;
+/// * blank lines at the start or end never produce an empty group;
+/// * each remaining line is one person, and whitespace inside it is ignored;
+/// * any other character outside `a` to `z` is skipped and reported with its
+///   1-based line number. A person whose line has no valid letters still
+///   counts, having answered nothing.
+fn parse_groups_checked(group_answer_blocks: &str) -> (Vec<Group>, Vec<InvalidAnswer>) {
+    let mut groups = Vec::new();
+    let mut invalid = Vec::new();
+    let mut people = Vec::new();
+
+    for (index, line) in group_answer_blocks.lines().enumerate() {
+        let answers = line.trim();
+        if answers.is_empty() {
+            if !people.is_empty() {
+                groups.push(Group { people: std::mem::take(&mut people) });
+            }
+            continue;
+        }
 
-    for group_1_answers in groups.first().unwrap().chars() {
-        question_set.insert(group_1_answers);
+        let mut person = QuestionSet::empty();
+        for character in answers.chars().filter(|character| !character.is_whitespace()) {
+            if !person.insert(character) {
+                invalid.push(InvalidAnswer { line: index + 1, character });
+            }
+        }
+        people.push(person);
     }
+    if !people.is_empty() {
+        groups.push(Group { people });
+    }
+
+    return (groups, invalid);
+}
+
+fn parse_groups(group_answer_blocks: &str) -> Vec<Group> {
+    return parse_groups_checked(group_answer_blocks).0;
+}
+
+fn sum_of_group_answers(group_answer_blocks: &str, query: GroupQuery) -> usize {
+    return parse_groups(group_answer_blocks).iter().map(|group| group.query(query).len()).sum();
+}
+
+
+fn question_letter(index: usize) -> char {
+    return (b'a' + index as u8) as char;
+}
 
-    let mut surviving_question_set = question_set;
-    for person_answer in groups[1..].iter() {
-        let mut new_surviving_question_set = HashSet::new();
-        for answer in person_answer.chars() {
-            if surviving_question_set.contains(&answer) {
-                new_surviving_question_set.insert(answer);
+/// Survey-wide statistics over every group's answers.
+struct SurveyReport {
+    groups: usize,
+    people: usize,
+    /// Group size to the number of groups of that size.
+    group_sizes: BTreeMap<usize, usize>,
+    /// People who answered each question, indexed from `a`.
+    answered: [usize; 26],
+    /// Groups in which everyone answered each question.
+    unanimous: [usize; 26],
+    /// People who answered both questions of each pair.
+    co_occurrence: [[usize; 26]; 26],
+}
+
+impl SurveyReport {
+    pub fn from_groups(groups: &[Group]) -> SurveyReport {
+        let mut report = SurveyReport {
+            groups: groups.len(),
+            people: 0,
+            group_sizes: BTreeMap::new(),
+            answered: [0; 26],
+            unanimous: [0; 26],
+            co_occurrence: [[0; 26]; 26],
+        };
+
+        for group in groups {
+            report.people += group.people.len();
+            *report.group_sizes.entry(group.people.len()).or_insert(0) += 1;
+
+            for (index, count) in group.question_counts().iter().enumerate() {
+                report.answered[index] += count;
+            }
+            for question in group.intersection().questions() {
+                report.unanimous[question as usize - 'a' as usize] += 1;
+            }
+            for person in &group.people {
+                let questions: Vec<usize> = person.questions().map(|q| q as usize - 'a' as usize).collect();
+                for (position, first) in questions.iter().enumerate() {
+                    for second in &questions[position + 1..] {
+                        report.co_occurrence[*first][*second] += 1;
+                        report.co_occurrence[*second][*first] += 1;
+                    }
+                }
             }
         }
 
-        surviving_question_set = new_surviving_question_set;
+        return report;
+    }
 
+    /// Questions ordered by how many groups answered them unanimously, most first.
+    pub fn most_unanimous(&self) -> Vec<(char, usize)> {
+        let mut questions: Vec<(char, usize)> = self.unanimous.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(index, count)| (question_letter(index), *count))
+            .collect();
+        questions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        return questions;
+    }
+
+    /// Every pair answered together by at least one person, most common first.
+    pub fn question_pairs(&self) -> Vec<(char, char, usize)> {
+        let mut pairs = Vec::new();
+        for first in 0..26 {
+            for second in first + 1..26 {
+                if self.co_occurrence[first][second] > 0 {
+                    pairs.push((question_letter(first), question_letter(second), self.co_occurrence[first][second]));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| b.2.cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+        return pairs;
     }
 
-    return surviving_question_set.len()
+    pub fn to_table(&self) -> String {
+        let mut table = String::new();
+        writeln!(table, "groups: {}  people: {}", self.groups, self.people).unwrap();
+
+        writeln!(table, "\n{:>10} {:>7}", "group size", "groups").unwrap();
+        for (size, groups) in &self.group_sizes {
+            writeln!(table, "{:>10} {:>7}", size, groups).unwrap();
+        }
+
+        writeln!(table, "\n{:>8} {:>8} {:>9}", "question", "answered", "unanimous").unwrap();
+        for index in 0..26 {
+            writeln!(table, "{:>8} {:>8} {:>9}", question_letter(index), self.answered[index], self.unanimous[index]).unwrap();
+        }
+
+        let most_unanimous: Vec<String> = self.most_unanimous().iter().take(5)
+            .map(|(question, groups)| format!("{} ({})", question, groups))
+            .collect();
+        writeln!(table, "\nmost often unanimous: {}", most_unanimous.join(", ")).unwrap();
+
+        writeln!(table, "\n{:>4} {:>6}", "pair", "people").unwrap();
+        for (first, second, people) in self.question_pairs().iter().take(10) {
+            writeln!(table, "{:>3}{} {:>6}", first, second, people).unwrap();
+        }
+        return table;
+    }
+
+    pub fn to_json(&self) -> String {
+        let report = SurveyReportJson {
+            groups: self.groups,
+            people: self.people,
+            group_sizes: &self.group_sizes,
+            questions: (0..26)
+                .map(|index| (question_letter(index), QuestionJson {
+                    answered: self.answered[index],
+                    unanimous: self.unanimous[index],
+                }))
+                .collect(),
+            most_unanimous: self.most_unanimous().iter().map(|(question, _)| *question).collect(),
+            co_occurrence: self.question_pairs().iter()
+                .map(|(first, second, people)| PairJson { pair: format!("{}{}", first, second), people: *people })
+                .collect(),
+        };
+        return serde_json::to_string(&report).unwrap();
+    }
+}
+
+/// The JSON shape of a `SurveyReport`: questions keyed by letter, pairs
+/// most common first.
+#[derive(Serialize)]
+struct SurveyReportJson<'a> {
+    groups: usize,
+    people: usize,
+    group_sizes: &'a BTreeMap<usize, usize>,
+    questions: BTreeMap<char, QuestionJson>,
+    most_unanimous: Vec<char>,
+    co_occurrence: Vec<PairJson>,
+}
+
+#[derive(Serialize)]
+struct QuestionJson {
+    answered: usize,
+    unanimous: usize,
+}
+
+#[derive(Serialize)]
+struct PairJson {
+    pair: String,
+    people: usize,
+}
+
+fn group_questions_count(group_answers: String) -> usize {
+    return Group::parse(&group_answers).union().len();
+}
+
+fn group_questions_count_v2(group_answers: String) -> usize {
+    return Group::parse(&group_answers).intersection().len();
 }
 
 fn test_group_questions_count() {
@@ -138,7 +468,136 @@ a
 a
 
 b".to_string();
-    assert_eq!(sum_of_group_answers(&block, false), 11);
+    assert_eq!(sum_of_group_answers(&block, GroupQuery::Union), 11);
+
+    assert_eq!(sum_of_group_answers(&block, GroupQuery::Intersection), 6);
+}
 
-    assert_eq!(sum_of_group_answers(&block, true), 6);
-}
\ No newline at end of file
+fn test_question_set() {
+    let set = QuestionSet::from_answers("abcz");
+    assert_eq!(set.len(), 4);
+    assert!(set.contains('z'));
+    assert!(!set.contains('d'));
+    assert_eq!(set.questions().collect::<String>(), "abcz");
+
+    let other = QuestionSet::from_answers("cdz");
+    assert_eq!(set.union(other).questions().collect::<String>(), "abcdz");
+    assert_eq!(set.intersection(other).questions().collect::<String>(), "cz");
+    assert_eq!(set.symmetric_difference(other).questions().collect::<String>(), "abd");
+
+    let mut set = QuestionSet::empty();
+    assert!(!set.insert('A'));
+    assert!(!set.insert('1'));
+    assert!(set.insert('q'));
+    assert_eq!(set, QuestionSet::from_answers("q"));
+    assert_eq!(QuestionSet::ALL.len(), 26);
+    assert!(QuestionSet::from_answers("XY1").is_empty());
+}
+
+fn test_group_queries() {
+    let group = Group::parse("abc
+abd
+ae
+f");
+    let letters = |query| group.query(query).questions().collect::<String>();
+    assert_eq!(letters(GroupQuery::Union), "abcdef");
+    assert_eq!(letters(GroupQuery::Intersection), "");
+    assert_eq!(letters(GroupQuery::SymmetricDifference), "acdef");
+    assert_eq!(letters(GroupQuery::AtLeast(2)), "ab");
+    assert_eq!(letters(GroupQuery::AtLeast(3)), "a");
+    assert_eq!(letters(GroupQuery::AtLeast(0)), "abcdef");
+    assert_eq!(letters(GroupQuery::ExactlyOne), "cdef");
+    assert_eq!(group.question_counts()[..6], [3, 2, 1, 1, 1, 1]);
+
+    let block = "abc
+
+a
+b
+c
+
+ab
+ac";
+    assert_eq!(sum_of_group_answers(block, GroupQuery::ExactlyOne), 3 + 3 + 2);
+    assert_eq!(sum_of_group_answers(block, GroupQuery::AtLeast(2)), 1);
+}
+
+fn test_survey_report() {
+    let groups = parse_groups("abc
+
+a
+b
+c
+
+ab
+ac
+
+a
+a
+a
+a
+
+b");
+    let report = SurveyReport::from_groups(&groups);
+    assert_eq!(report.groups, 5);
+    assert_eq!(report.people, 11);
+    assert_eq!(report.group_sizes.iter().map(|(size, groups)| (*size, *groups)).collect::<Vec<(usize, usize)>>(), vec![(1, 2), (2, 1), (3, 1), (4, 1)]);
+    assert_eq!(report.answered[..3], [8, 4, 3]);
+    assert_eq!(report.unanimous[..3], [3, 2, 1]);
+    assert_eq!(report.most_unanimous(), vec![('a', 3), ('b', 2), ('c', 1)]);
+    assert_eq!(report.co_occurrence[0][1], 2);
+    assert_eq!(report.co_occurrence[1][0], 2);
+    assert_eq!(report.question_pairs(), vec![('a', 'b', 2), ('a', 'c', 2), ('b', 'c', 1)]);
+}
+
+fn test_survey_report_output() {
+    let report = SurveyReport::from_groups(&parse_groups("ab
+
+a
+a"));
+    assert_eq!(report.to_json(), "{\"groups\":2,\"people\":3,\"group_sizes\":{\"1\":1,\"2\":1},\"questions\":{".to_string()
+        + "\"a\":{\"answered\":3,\"unanimous\":2},\"b\":{\"answered\":1,\"unanimous\":1},"
+        + &(2..26).map(|index| format!("\"{}\":{{\"answered\":0,\"unanimous\":0}}", question_letter(index))).collect::<Vec<String>>().join(",")
+        + "},\"most_unanimous\":[\"a\",\"b\"],\"co_occurrence\":[{\"pair\":\"ab\",\"people\":1}]}");
+
+    let table = report.to_table();
+    assert!(table.starts_with("groups: 2  people: 3\n"));
+    assert!(table.contains("\n       a        3         2\n"));
+    assert!(table.contains("\nmost often unanimous: a (2), b (1)\n"));
+    assert!(table.ends_with("  ab      1\n"));
+}
+
+fn test_malformed_groups() {
+    assert_eq!(group_questions_count_v2("".to_string()), 0);
+    assert_eq!(group_questions_count("".to_string()), 0);
+    assert_eq!(group_questions_count_v2("ab\r\nac\r\n".to_string()), 1);
+
+    let block = "abc\n\nab\nac\n\n";
+    assert_eq!(parse_groups(block).len(), 2);
+    assert_eq!(sum_of_group_answers(block, GroupQuery::Intersection), 3 + 1);
+
+    let crlf = "abc\r\n\r\na\r\nb\r\nc\r\n\r\nab\r\nac\r\n";
+    assert_eq!(parse_groups(crlf).len(), 3);
+    assert_eq!(sum_of_group_answers(crlf, GroupQuery::Union), 3 + 3 + 3);
+    assert_eq!(sum_of_group_answers(crlf, GroupQuery::Intersection), 4);
+
+    let spaced = "\n\n  ab  \n ac\n   \n\t\n\n\nb\n\n\n";
+    let groups = parse_groups(spaced);
+    assert_eq!(groups.iter().map(|group| group.people.len()).collect::<Vec<usize>>(), vec![2, 1]);
+    assert_eq!(sum_of_group_answers(spaced, GroupQuery::Intersection), 1 + 1);
+
+    let (groups, invalid) = parse_groups_checked("a b1\nA-c\n\n??\nx");
+    assert_eq!(invalid, vec![
+        InvalidAnswer { line: 1, character: '1' },
+        InvalidAnswer { line: 2, character: 'A' },
+        InvalidAnswer { line: 2, character: '-' },
+        InvalidAnswer { line: 4, character: '?' },
+        InvalidAnswer { line: 4, character: '?' },
+    ]);
+    assert_eq!(groups[0].union().questions().collect::<String>(), "abc");
+    assert_eq!(groups[0].intersection().len(), 0);
+    assert_eq!(groups[1].people.len(), 2);
+    assert_eq!(groups[1].intersection().len(), 0);
+
+    assert_eq!(parse_groups_checked("").0.len(), 0);
+    assert_eq!(parse_groups_checked("\r\n \n").0.len(), 0);
+}
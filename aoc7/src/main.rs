@@ -1,18 +1,82 @@
-use petgraph::graphmap::DiGraphMap;
-use petgraph::visit::{Bfs, Dfs};
+use cached::{Cached, UnboundCache};
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::Bfs;
 use petgraph::prelude::*;
-use regex::Regex;
-use std::fs::File;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::env;
+use std::fmt;
+use std::fmt::Write;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader};
+use std::process;
 fn main() {
     test_rule_generate();
-    let data = read_data("src/input.txt");
-    let rules = rule_generate_contains(&data);
-    let available_bags = find_contains_num(&rules.0, "shiny gold");
+    test_bag_rules_queries();
+    test_bag_rules_serde();
+    test_count_contained_overflow();
+    test_validate_rules();
+    test_export_dot();
+    test_export_json();
+    test_parse_rule();
+    test_parse_checked();
+    test_shortest_path();
+    test_within_levels();
+    test_deepest_nesting();
+    test_contents_breakdown();
+    test_bag_query();
+
+    let args: Vec<String> = env::args().collect();
+    let rules = match args.get(1).map(|command| command.as_str()) {
+        Some("import") => {
+            let path = args.get(2).expect("import needs a JSON file");
+            let json = fs::read_to_string(path).expect("Something went wrong reading the file");
+            BagRules::from_json(&json).expect("Error parsing bag rules JSON")
+        }
+        _ => {
+            let (rules, errors) = BagRules::parse_checked(&read_data("src/input.txt"));
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            rules
+        }
+    };
+    match args.get(1).map(|command| command.as_str()) {
+        Some("dot") => {
+            print!("{}", rules.export_from(args.get(2)).to_dot());
+            return;
+        }
+        Some("json") => {
+            println!("{}", rules.export_from(args.get(2)).to_json());
+            return;
+        }
+        Some("query") => {
+            let query = BagQuery::parse(&args[2..]).unwrap_or_else(|usage| {
+                eprintln!("{}", usage);
+                process::exit(2);
+            });
+            match rules.run_query(&query) {
+                Ok(output) => print!("{}", output),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    for issue in rules.validate() {
+        eprintln!("{}", issue);
+    }
+    let available_bags = rules.all_containers("shiny gold").len();
     println!("bags {}", available_bags);
 
-    let number_bags_required = find_required(&rules.1, "shiny gold");
-    println!("bags required {}", number_bags_required);
+    match rules.count_contained("shiny gold") {
+        Ok(number_bags_required) => println!("bags required {}", number_bags_required),
+        Err(e) => println!("bags required could not be counted: {}", e),
+    }
 }
 
 fn read_data(filename: &str) -> Vec<String> {
@@ -24,72 +88,589 @@ fn read_data(filename: &str) -> Vec<String> {
     return rules;
 }
 
-fn rule_generate_contains(rules: &Vec<String>) -> (DiGraphMap::<&str, f32>, DiGraphMap::<&str, f32>) {
-    let mut bags = DiGraphMap::<&str, f32>::new();
-    let mut switch_direction_bags = DiGraphMap::<&str, f32>::new();
+/// Bag rules as a single graph: an edge `outer -> inner` weighted `n` means
+/// an `outer` bag must directly contain `n` `inner` bags. `definitions` keeps
+/// the 1-based lines each bag's own rule appeared on.
+#[derive(Serialize, Deserialize)]
+#[serde(from = "SerializedBagRules")]
+struct BagRules {
+    graph: DiGraph<String, u32>,
+    definitions: BTreeMap<String, Vec<usize>>,
+    #[serde(skip)]
+    index: HashMap<String, NodeIndex>,
+}
+
+#[derive(Deserialize)]
+struct SerializedBagRules {
+    graph: DiGraph<String, u32>,
+    #[serde(default)]
+    definitions: BTreeMap<String, Vec<usize>>,
+}
+
+impl From<SerializedBagRules> for BagRules {
+    fn from(rules: SerializedBagRules) -> BagRules {
+        let graph = rules.graph;
+        let index = graph.node_indices().map(|node| (graph[node].clone(), node)).collect();
+        return BagRules { graph, definitions: rules.definitions, index };
+    }
+}
+
+impl BagRules {
+    pub fn new() -> BagRules {
+        BagRules {
+            graph: DiGraph::new(),
+            definitions: BTreeMap::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    /// Builds the graph from every rule that parses, skipping the rest.
+    pub fn parse(rules: &[String]) -> BagRules {
+        return BagRules::parse_checked(rules).0;
+    }
+
+    /// Like `parse`, also returning every rule that could not be read.
+    /// Blank lines are ignored.
+    pub fn parse_checked(rules: &[String]) -> (BagRules, Vec<RuleParseError>) {
+        let mut bags = BagRules::new();
+        let mut errors = Vec::new();
+
+        for (index, rule) in rules.iter().enumerate() {
+            if rule.trim().is_empty() {
+                continue;
+            }
+            match parse_rule(rule) {
+                Ok((outer, contents)) => {
+                    bags.define(&outer, index + 1);
+                    for (count, inner) in contents {
+                        bags.add_rule(&outer, &inner, count);
+                    }
+                }
+                Err(reason) => errors.push(RuleParseError {
+                    line: index + 1,
+                    rule: rule.to_string(),
+                    reason,
+                }),
+            }
+        }
+        return (bags, errors);
+    }
+
+    pub fn add_bag(&mut self, bag: &str) -> NodeIndex {
+        if let Some(node) = self.index.get(bag) {
+            return *node;
+        }
+        let node = self.graph.add_node(bag.to_string());
+        self.index.insert(bag.to_string(), node);
+        return node;
+    }
+
+    /// Records that `bag`'s own rule was read from `line`.
+    pub fn define(&mut self, bag: &str, line: usize) -> NodeIndex {
+        self.definitions.entry(bag.to_string()).or_default().push(line);
+        return self.add_bag(bag);
+    }
+
+    pub fn add_rule(&mut self, outer: &str, inner: &str, count: u32) {
+        let outer = self.add_bag(outer);
+        let inner = self.add_bag(inner);
+        self.graph.update_edge(outer, inner, count);
+    }
+
+    pub fn node(&self, bag: &str) -> Option<NodeIndex> {
+        return self.index.get(bag).cloned();
+    }
+
+    pub fn bags(&self) -> impl Iterator<Item = &str> {
+        return self.graph.node_indices().map(move |node| self.graph[node].as_str());
+    }
+
+    fn neighbors(&self, bag: &str, direction: Direction) -> Vec<(String, u32)> {
+        let node = match self.node(bag) {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+        let mut neighbors: Vec<(String, u32)> = self.graph.edges_directed(node, direction)
+            .map(|edge| {
+                let other = if direction == Outgoing { edge.target() } else { edge.source() };
+                (self.graph[other].clone(), *edge.weight())
+            })
+            .collect();
+        neighbors.sort();
+        return neighbors;
+    }
+
+    /// Bags `bag` must directly hold, with how many of each.
+    pub fn contents(&self, bag: &str) -> Vec<(String, u32)> {
+        return self.neighbors(bag, Outgoing);
+    }
+
+    /// Bags that directly hold `bag`, with how many of it they hold.
+    pub fn containers(&self, bag: &str) -> Vec<(String, u32)> {
+        return self.neighbors(bag, Incoming);
+    }
+
+    /// Every bag that eventually contains `bag`, sorted by name.
+    pub fn all_containers(&self, bag: &str) -> Vec<String> {
+        let node = match self.node(bag) {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+        let reversed = petgraph::visit::Reversed(&self.graph);
+        let mut bfs = Bfs::new(reversed, node);
+        let mut bags = Vec::new();
+        while let Some(visited) = bfs.next(reversed) {
+            if visited != node {
+                bags.push(self.graph[visited].clone());
+            }
+        }
+        bags.sort();
+        return bags;
+    }
+
+    /// Everything `bag` holds, directly or not, with `bag` itself.
+    pub fn subgraph(&self, bag: &str) -> BagRules {
+        let mut subgraph = BagRules::new();
+        if self.node(bag).is_none() {
+            return subgraph;
+        }
+
+        // Breadth first over contents sorted by name, so exports are stable.
+        let mut queue = VecDeque::new();
+        let mut seen = HashSet::new();
+        queue.push_back(bag.to_string());
+        seen.insert(bag.to_string());
+        while let Some(outer) = queue.pop_front() {
+            subgraph.add_bag(&outer);
+            if let Some(lines) = self.definitions.get(&outer) {
+                subgraph.definitions.insert(outer.clone(), lines.clone());
+            }
+            for (inner, count) in self.contents(&outer) {
+                subgraph.add_rule(&outer, &inner, count);
+                if seen.insert(inner.clone()) {
+                    queue.push_back(inner);
+                }
+            }
+        }
+        return subgraph;
+    }
+
+    fn export_from(&self, bag: Option<&String>) -> BagRules {
+        match bag {
+            Some(bag) => self.subgraph(bag),
+            None => self.subgraph_all(),
+        }
+    }
+
+    fn subgraph_all(&self) -> BagRules {
+        let mut copy = BagRules::new();
+        for bag in self.bags() {
+            copy.add_bag(bag);
+        }
+        for edge in self.graph.edge_references() {
+            copy.add_rule(&self.graph[edge.source()], &self.graph[edge.target()], *edge.weight());
+        }
+        copy.definitions = self.definitions.clone();
+        return copy;
+    }
+
+    /// Graphviz source with one node per bag and edges labelled by count.
+    pub fn to_dot(&self) -> String {
+        let quote = |bag: &str| format!("\"{}\"", bag.replace('\\', "\\\\").replace('"', "\\\""));
+
+        let mut dot = String::new();
+        writeln!(dot, "digraph bags {{").unwrap();
+        for bag in self.bags() {
+            writeln!(dot, "    {};", quote(bag)).unwrap();
+        }
+        for edge in self.graph.edge_references() {
+            writeln!(dot, "    {} -> {} [label=\"{}\"];",
+                quote(&self.graph[edge.source()]), quote(&self.graph[edge.target()]), edge.weight()).unwrap();
+        }
+        writeln!(dot, "}}").unwrap();
+        return dot;
+    }
+
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string(self).unwrap();
+    }
+
+    pub fn from_json(json: &str) -> Result<BagRules, serde_json::Error> {
+        return serde_json::from_str(json);
+    }
+
+    /// Checks the rule set for cycles, bags defined more than once, bags
+    /// used but never defined, and bags no root bag can reach. A root is a
+    /// bag nothing else contains.
+    pub fn validate(&self) -> Vec<RuleIssue> {
+        let mut issues = Vec::new();
+
+        let mut cycles: Vec<Vec<String>> = petgraph::algo::tarjan_scc(&self.graph).into_iter()
+            .filter(|component| component.len() > 1 || self.graph.contains_edge(component[0], component[0]))
+            .map(|component| {
+                let mut bags: Vec<String> = component.iter().map(|node| self.graph[*node].clone()).collect();
+                bags.sort();
+                bags
+            })
+            .collect();
+        cycles.sort();
+        issues.extend(cycles.into_iter().map(RuleIssue::Cycle));
+
+        for (bag, lines) in &self.definitions {
+            if lines.len() > 1 {
+                issues.push(RuleIssue::DuplicateDefinition { bag: bag.clone(), lines: lines.clone() });
+            }
+        }
+
+        let mut bags: Vec<&str> = self.bags().collect();
+        bags.sort();
+        for bag in &bags {
+            if !self.definitions.contains_key(*bag) {
+                let referenced_by = self.containers(bag).into_iter().map(|(outer, _)| outer).collect();
+                issues.push(RuleIssue::Undefined { bag: bag.to_string(), referenced_by });
+            }
+        }
+
+        let mut reachable = HashSet::new();
+        for root in self.graph.externals(Incoming) {
+            let mut bfs = Bfs::new(&self.graph, root);
+            while let Some(visited) = bfs.next(&self.graph) {
+                reachable.insert(visited);
+            }
+        }
+        for bag in &bags {
+            if !reachable.contains(&self.index[*bag]) {
+                issues.push(RuleIssue::Unreachable(bag.to_string()));
+            }
+        }
+
+        return issues;
+    }
+
+    /// Total number of bags inside one `bag`.
+    pub fn count_contained(&self, bag: &str) -> Result<u128, CountError> {
+        let node = match self.node(bag) {
+            Some(node) => node,
+            None => return Ok(0),
+        };
+        return self.count_with_cache(node, &mut UnboundCache::new());
+    }
+
+    /// Totals for every bag, sharing one cache so each bag is counted once.
+    pub fn all_contained_counts(&self) -> Result<HashMap<String, u128>, CountError> {
+        let mut totals = UnboundCache::new();
+        let mut counts = HashMap::new();
+        for node in self.graph.node_indices() {
+            counts.insert(self.graph[node].clone(), self.count_with_cache(node, &mut totals)?);
+        }
+        return Ok(counts);
+    }
+
+    /// Post-order walk with an explicit stack, so long nesting chains can't
+    /// overflow the call stack. Each bag's total is computed once and kept in
+    /// `totals`; a bag met again while still on the path is a cycle.
+    fn count_with_cache(&self, start: NodeIndex, totals: &mut UnboundCache<NodeIndex, u128>) -> Result<u128, CountError> {
+        let contents = |node: NodeIndex| -> Vec<(NodeIndex, u32)> {
+            self.graph.edges_directed(node, Outgoing).map(|edge| (edge.target(), *edge.weight())).collect()
+        };
+
+        let mut on_path = HashSet::new();
+        let mut stack = vec![(start, contents(start), 0)];
+        on_path.insert(start);
+
+        while let Some((node, edges, next)) = stack.last_mut() {
+            if let Some((inner, _)) = edges.get(*next) {
+                let inner = *inner;
+                *next += 1;
+                if totals.cache_get(&inner).is_some() {
+                    continue;
+                }
+                if !on_path.insert(inner) {
+                    return Err(CountError::Cycle { bag: self.graph[inner].clone() });
+                }
+                stack.push((inner, contents(inner), 0));
+                continue;
+            }
+
+            let mut total: u128 = 0;
+            for (inner, count) in edges.iter() {
+                let inner_total = *totals.cache_get(inner).unwrap();
+                total = inner_total.checked_add(1)
+                    .and_then(|bags| bags.checked_mul(*count as u128))
+                    .and_then(|bags| bags.checked_add(total))
+                    .ok_or_else(|| CountError::Overflow { bag: self.graph[*node].clone() })?;
+            }
+            let node = *node;
+            totals.cache_set(node, total);
+            on_path.remove(&node);
+            stack.pop();
+        }
+
+        return Ok(*totals.cache_get(&start).unwrap());
+    }
+
+    fn topological_order(&self) -> Result<Vec<NodeIndex>, CountError> {
+        return petgraph::algo::toposort(&self.graph, None)
+            .map_err(|cycle| CountError::Cycle { bag: self.graph[cycle.node_id()].clone() });
+    }
+
+    /// Fewest nesting steps from `outer` down to `inner`, both included.
+    pub fn shortest_path(&self, outer: &str, inner: &str) -> Option<Vec<String>> {
+        self.node(outer)?;
+        self.node(inner)?;
+
+        let mut parents: HashMap<String, String> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(outer.to_string());
+        while let Some(bag) = queue.pop_front() {
+            if bag == inner {
+                let mut path = vec![bag];
+                while let Some(parent) = parents.get(path.last().unwrap()) {
+                    path.push(parent.clone());
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for (next, _) in self.contents(&bag) {
+                if next != outer && !parents.contains_key(&next) {
+                    parents.insert(next.clone(), bag.clone());
+                    queue.push_back(next);
+                }
+            }
+        }
+        return None;
+    }
+
+    /// Bags nested at most `levels` deep inside `bag`, each with the
+    /// shallowest level it appears at, ordered by level then name.
+    pub fn within_levels(&self, bag: &str, levels: usize) -> Vec<(String, usize)> {
+        let mut found: HashMap<String, usize> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((bag.to_string(), 0));
+        while let Some((outer, level)) = queue.pop_front() {
+            if level == levels {
+                continue;
+            }
+            for (inner, _) in self.contents(&outer) {
+                if inner != bag && !found.contains_key(&inner) {
+                    found.insert(inner.clone(), level + 1);
+                    queue.push_back((inner, level + 1));
+                }
+            }
+        }
+
+        let mut within: Vec<(String, usize)> = found.into_iter().collect();
+        within.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        return within;
+    }
+
+    /// The bag holding the longest chain of bags within bags, and that
+    /// chain's length. Ties go to the first name alphabetically.
+    pub fn deepest_nesting(&self) -> Result<Option<(String, usize)>, CountError> {
+        let mut depths: HashMap<NodeIndex, usize> = HashMap::new();
+        for node in self.topological_order()?.into_iter().rev() {
+            let depth = self.graph.neighbors_directed(node, Outgoing)
+                .map(|inner| depths[&inner] + 1)
+                .max()
+                .unwrap_or(0);
+            depths.insert(node, depth);
+        }
+
+        return Ok(depths.into_iter()
+            .map(|(node, depth)| (self.graph[node].clone(), depth))
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0))));
+    }
 
-    let bag_rule_regex = Regex::new(r"(?P<number>\d) (?P<bag>\w+ \w+) bag[s]?").unwrap();
-    for rule in rules {
-        let key_value: Vec<&str> = rule.split(" contain ").collect();
-        let main_bag = key_value[0].strip_suffix(" bags").unwrap();
-        bags.add_node(main_bag);
-        switch_direction_bags.add_node(main_bag);
+    /// How many of each bag type one `bag` holds in total, at every level.
+    pub fn contents_breakdown(&self, bag: &str) -> Result<BTreeMap<String, u128>, CountError> {
+        let subgraph = self.subgraph(bag);
+        let start = match subgraph.node(bag) {
+            Some(node) => node,
+            None => return Ok(BTreeMap::new()),
+        };
 
-        for bag_rules in key_value.last().unwrap().split(',').collect::<Vec<&str>>().iter() {
-            let captured_rules = bag_rule_regex.captures(bag_rules);
-            if captured_rules.is_some() {
-                let rule = captured_rules.unwrap();
-                let connecting_bag = &rule.name("bag").unwrap().as_str();
-                bags.add_node(connecting_bag);
-                switch_direction_bags.add_node(connecting_bag);
-                bags.add_edge(connecting_bag, main_bag, rule["number"].parse::<f32>().unwrap().clone());
-                switch_direction_bags.add_edge(main_bag, connecting_bag, rule["number"].parse::<f32>().unwrap().clone());
+        let mut totals: HashMap<NodeIndex, u128> = HashMap::new();
+        totals.insert(start, 1);
+        for node in subgraph.topological_order()? {
+            let outer_total = totals.get(&node).cloned().unwrap_or(0);
+            for edge in subgraph.graph.edges_directed(node, Outgoing) {
+                let total = totals.entry(edge.target()).or_insert(0);
+                *total = outer_total.checked_mul(*edge.weight() as u128)
+                    .and_then(|bags| bags.checked_add(*total))
+                    .ok_or_else(|| CountError::Overflow { bag: subgraph.graph[node].clone() })?;
             }
         }
+
+        totals.remove(&start);
+        return Ok(totals.into_iter().map(|(node, total)| (subgraph.graph[node].clone(), total)).collect());
+    }
+
+    pub fn run_query(&self, query: &BagQuery) -> Result<String, CountError> {
+        let mut output = String::new();
+        match query {
+            BagQuery::Path { from, to } => match self.shortest_path(from, to) {
+                Some(path) => writeln!(output, "{}", path.join(" -> ")).unwrap(),
+                None => writeln!(output, "{} cannot hold {}", from, to).unwrap(),
+            },
+            BagQuery::Within { bag, levels } => {
+                for (inner, level) in self.within_levels(bag, *levels) {
+                    writeln!(output, "{} {}", level, inner).unwrap();
+                }
+            }
+            BagQuery::Deepest => match self.deepest_nesting()? {
+                Some((bag, depth)) => writeln!(output, "{} nests {} levels deep", bag, depth).unwrap(),
+                None => writeln!(output, "no bags").unwrap(),
+            },
+            BagQuery::Breakdown { bag } => {
+                let breakdown = self.contents_breakdown(bag)?;
+                for (inner, count) in &breakdown {
+                    writeln!(output, "{} {}", count, inner).unwrap();
+                }
+                writeln!(output, "total {}", self.count_contained(bag)?).unwrap();
+            }
+        }
+        return Ok(output);
     }
-    return (bags, switch_direction_bags);
 }
 
-fn find_contains_num(bag_graph: &DiGraphMap::<&str, f32>, starting_bag: &str) -> usize {
-    let contained_bags = find_contains(bag_graph, starting_bag);
-    return contained_bags.len() - 1;
+#[derive(Debug, PartialEq)]
+enum BagQuery {
+    Path { from: String, to: String },
+    Within { bag: String, levels: usize },
+    Deepest,
+    Breakdown { bag: String },
 }
 
+impl BagQuery {
+    /// Reads a query from command line words, e.g. `path "shiny gold" "faded blue"`,
+    /// `within "shiny gold" 2`, `deepest` or `breakdown "shiny gold"`.
+    pub fn parse(words: &[String]) -> Result<BagQuery, String> {
+        let words: Vec<&str> = words.iter().map(|word| word.as_str()).collect();
+        match words.as_slice() {
+            ["path", from, to] => Ok(BagQuery::Path { from: from.to_string(), to: to.to_string() }),
+            ["within", bag, levels] => Ok(BagQuery::Within {
+                bag: bag.to_string(),
+                levels: levels.parse().map_err(|_| format!("expected a number of levels, found {:?}", levels))?,
+            }),
+            ["deepest"] => Ok(BagQuery::Deepest),
+            ["breakdown", bag] => Ok(BagQuery::Breakdown { bag: bag.to_string() }),
+            _ => Err("usage: query path <from> <to> | within <bag> <levels> | deepest | breakdown <bag>".to_string()),
+        }
+    }
+}
 
-fn find_contains(bag_graph: &DiGraphMap::<&str, f32>, starting_bag: &str) -> Vec<String> {
-    let mut bags = Vec::new();
-    let mut dfs = Bfs::new(bag_graph, starting_bag);
-    while let Some(visited) = dfs.next(bag_graph) {
-        bags.push(visited.to_string());
+#[derive(Debug, PartialEq)]
+enum CountError {
+    Overflow { bag: String },
+    Cycle { bag: String },
+}
+
+impl fmt::Display for CountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CountError::Overflow { bag } => write!(f, "the number of bags inside {} overflows", bag),
+            CountError::Cycle { bag } => write!(f, "{} eventually contains itself", bag),
+        }
+    }
+}
+
+impl std::error::Error for CountError {}
+
+#[derive(Debug, PartialEq)]
+struct RuleParseError {
+    line: usize,
+    rule: String,
+    reason: String,
+}
+
+impl fmt::Display for RuleParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {} in {:?}", self.line, self.reason, self.rule)
     }
-    return bags;
 }
 
-fn dfs<'a>(bag_graph: &DiGraphMap::<&'a str, f32>, starting_bag: &str) -> f32 {
-    let mut count = 0.0;
+impl std::error::Error for RuleParseError {}
+
+/// Reads `<colour> bag[s]`, returning the colour with single spaces between
+/// its words. Colours may have any number of words.
+fn parse_bag(words: &[&str]) -> Result<String, String> {
+    match words.split_last() {
+        Some((&"bag", colour)) | Some((&"bags", colour)) if !colour.is_empty() => Ok(colour.join(" ")),
+        Some((_, _)) if words.len() == 1 => Err(format!("expected a colour before {:?}", words[0])),
+        Some(_) => Err(format!("expected \"bag\" or \"bags\" after {:?}", words.join(" "))),
+        None => Err("expected a bag".to_string()),
+    }
+}
+
+/// Parses `<colour> bag[s] contain <n> <colour> bag[s], ... .` or
+/// `<colour> bag[s] contain no other bags.` into the outer colour and its
+/// contents. Whitespace between words and the final full stop are optional.
+fn parse_rule(rule: &str) -> Result<(String, Vec<(u32, String)>), String> {
+    let rule = rule.trim();
+    let rule = rule.strip_suffix('.').unwrap_or(rule);
+
+    let words: Vec<&str> = rule.split_whitespace().collect();
+    let contain = words.iter().position(|word| *word == "contain")
+        .ok_or_else(|| "missing \"contain\"".to_string())?;
+    let outer = parse_bag(&words[..contain])?;
 
-    let mut dfs_handler = Dfs::new(bag_graph, starting_bag);
-    while let Some(visited) = dfs_handler.next(bag_graph) {
-        if bag_graph.contains_edge(starting_bag, visited) {
-            let edge_multiplier = bag_graph.edge_weight(starting_bag, visited).unwrap();
-            count += edge_multiplier + edge_multiplier * dfs(bag_graph, visited);
+    let contents = words[contain + 1..].join(" ");
+    if contents.is_empty() {
+        return Err(format!("nothing after \"contain\" for {}", outer));
+    }
+    if contents == "no other bags" || contents == "no other bag" {
+        return Ok((outer, Vec::new()));
+    }
+
+    let mut inner_bags = Vec::new();
+    for item in contents.split(',') {
+        let item_words: Vec<&str> = item.split_whitespace().collect();
+        let (count, bag) = match item_words.split_first() {
+            Some((count, bag)) => (count, bag),
+            None => return Err("empty item in contents".to_string()),
+        };
+        let count: u32 = count.parse().map_err(|_| format!("expected a quantity, found {:?}", count))?;
+        if count == 0 {
+            return Err(format!("quantity of {} must be at least 1", bag.join(" ")));
+        }
+        let bag = parse_bag(bag)?;
+        // The graph keeps one count per pair of bags, so a repeat would
+        // silently replace the first count.
+        if inner_bags.iter().any(|(_, seen)| *seen == bag) {
+            return Err(format!("duplicate inner bag {}", bag));
         }
+        inner_bags.push((count, bag));
     }
-    return count;
+    return Ok((outer, inner_bags));
 }
 
-fn find_required(bag_graph: &DiGraphMap::<&str, f32>, starting_bag: &str) -> f32 {
-    let mut number_of_bags = 0.0;
+#[derive(Debug, PartialEq)]
+enum RuleIssue {
+    /// Bags that eventually contain themselves, sorted by name.
+    Cycle(Vec<String>),
+    DuplicateDefinition { bag: String, lines: Vec<usize> },
+    Undefined { bag: String, referenced_by: Vec<String> },
+    Unreachable(String),
+}
 
-    for neighbor in bag_graph.neighbors_directed(starting_bag, Outgoing) {
-        let edge = bag_graph.edge_weight(starting_bag, neighbor).unwrap();
-        number_of_bags += edge + edge * dfs(bag_graph, neighbor);
+impl fmt::Display for RuleIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleIssue::Cycle(bags) => write!(f, "cycle between {}", bags.join(", ")),
+            RuleIssue::DuplicateDefinition { bag, lines } => {
+                let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+                write!(f, "{} is defined on lines {}", bag, lines.join(", "))
+            }
+            RuleIssue::Undefined { bag, referenced_by } => {
+                write!(f, "{} is never defined (used by {})", bag, referenced_by.join(", "))
+            }
+            RuleIssue::Unreachable(bag) => write!(f, "{} cannot be reached from any outermost bag", bag),
+        }
     }
-    return number_of_bags;
 }
 
-fn test_rule_generate() {
-    let rules = "light red bags contain 1 bright white bag, 2 muted yellow bags.
+fn example_rules() -> Vec<String> {
+    return "light red bags contain 1 bright white bag, 2 muted yellow bags.
 dark orange bags contain 3 bright white bags, 4 muted yellow bags.
 bright white bags contain 1 shiny gold bag.
 muted yellow bags contain 2 shiny gold bags, 9 faded blue bags.
@@ -98,30 +679,327 @@ dark olive bags contain 3 faded blue bags, 4 dotted black bags.
 vibrant plum bags contain 5 faded blue bags, 6 dotted black bags.
 faded blue bags contain no other bags.
 dotted black bags contain no other bags.".lines().map(|s| s.to_string()).collect();
+}
 
-    let generate = rule_generate_contains(&rules);
-
-    let dfs = find_contains(&generate.0, "shiny gold");
+fn test_rule_generate() {
+    let rules = BagRules::parse(&example_rules());
 
-    assert_eq!(dfs, vec![
-        "shiny gold".to_string(),
+    assert_eq!(rules.all_containers("shiny gold"), vec![
         "bright white".to_string(),
-        "muted yellow".to_string(),
-        "light red".to_string(),
         "dark orange".to_string(),
+        "light red".to_string(),
+        "muted yellow".to_string(),
     ]);
 
-    let number = find_required(&generate.1, "shiny gold");
-    assert_eq!(number, 32.0);
+    let number = rules.count_contained("shiny gold");
+    assert_eq!(number, Ok(32));
 
-    let rules_2 = "shiny gold bags contain 2 dark red bags.
+    let rules_2: Vec<String> = "shiny gold bags contain 2 dark red bags.
 dark red bags contain 2 dark orange bags.
 dark orange bags contain 2 dark yellow bags.
 dark yellow bags contain 2 dark green bags.
 dark green bags contain 2 dark blue bags.
 dark blue bags contain 2 dark violet bags.
 dark violet bags contain no other bags.".lines().map(|s| s.to_string()).collect();
-    let generate_2 = rule_generate_contains(&rules_2);
-    let number = find_required(&generate_2.1, "shiny gold");
-    assert_eq!(number, 126.0);
-}
\ No newline at end of file
+    let generate_2 = BagRules::parse(&rules_2);
+    let number = generate_2.count_contained("shiny gold");
+    assert_eq!(number, Ok(126));
+}
+
+fn test_bag_rules_queries() {
+    let rules = BagRules::parse(&example_rules());
+    assert_eq!(rules.bags().count(), 9);
+    assert_eq!(rules.contents("shiny gold"), vec![
+        ("dark olive".to_string(), 1),
+        ("vibrant plum".to_string(), 2),
+    ]);
+    assert_eq!(rules.containers("shiny gold"), vec![
+        ("bright white".to_string(), 1),
+        ("muted yellow".to_string(), 2),
+    ]);
+    assert_eq!(rules.contents("faded blue"), vec![]);
+    assert_eq!(rules.containers("light red"), vec![]);
+    assert_eq!(rules.all_containers("no such"), Vec::<String>::new());
+    assert_eq!(rules.count_contained("no such"), Ok(0));
+}
+
+fn test_bag_rules_serde() {
+    let rules = BagRules::parse(&example_rules());
+    let json = serde_json::to_string(&rules).unwrap();
+    let restored: BagRules = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(restored.bags().collect::<Vec<&str>>(), rules.bags().collect::<Vec<&str>>());
+    assert_eq!(restored.contents("muted yellow"), rules.contents("muted yellow"));
+    assert_eq!(restored.all_containers("shiny gold"), rules.all_containers("shiny gold"));
+    assert_eq!(restored.count_contained("shiny gold"), Ok(32));
+    assert_eq!(restored.validate(), vec![]);
+}
+
+fn chain_rules(length: usize, count: u32) -> BagRules {
+    let mut rules = BagRules::new();
+    for depth in 0..length {
+        rules.add_rule(&format!("bag {}", depth), &format!("bag {}", depth + 1), count);
+    }
+    return rules;
+}
+
+fn test_count_contained_overflow() {
+    // 2 + 4 + ... + 2^100 = 2^101 - 2, which f32 could not hold exactly.
+    let rules = chain_rules(100, 2);
+    assert_eq!(rules.count_contained("bag 0"), Ok((1u128 << 101) - 2));
+
+    let rules = chain_rules(130, 2);
+    assert_eq!(rules.count_contained("bag 0"), Err(CountError::Overflow { bag: "bag 2".to_string() }));
+    assert_eq!(rules.count_contained("bag 10"), Ok((1u128 << 121) - 2));
+
+    let rules = chain_rules(100_000, 1);
+    assert_eq!(rules.count_contained("bag 0"), Ok(100_000));
+
+    // Every level holds two of the next through two paths, so an unmemoized
+    // walk would visit 2^60 paths.
+    let mut diamond = BagRules::new();
+    for depth in 0..60 {
+        diamond.add_rule(&format!("top {}", depth), &format!("left {}", depth), 1);
+        diamond.add_rule(&format!("top {}", depth), &format!("right {}", depth), 1);
+        diamond.add_rule(&format!("left {}", depth), &format!("top {}", depth + 1), 1);
+        diamond.add_rule(&format!("right {}", depth), &format!("top {}", depth + 1), 1);
+    }
+    assert_eq!(diamond.count_contained("top 0"), Ok((1u128 << 62) - 4));
+
+    let counts = BagRules::parse(&example_rules()).all_contained_counts().unwrap();
+    assert_eq!(counts["shiny gold"], 32);
+    assert_eq!(counts["faded blue"], 0);
+    assert_eq!(counts["vibrant plum"], 11);
+
+    let mut cyclic = BagRules::new();
+    cyclic.add_rule("a", "b", 1);
+    cyclic.add_rule("b", "a", 1);
+    assert_eq!(cyclic.count_contained("a"), Err(CountError::Cycle { bag: "a".to_string() }));
+}
+
+fn test_validate_rules() {
+    assert_eq!(BagRules::parse(&example_rules()).validate(), vec![]);
+
+    let rules: Vec<String> = "light red bags contain 1 bright white bag, 2 muted yellow bags.
+bright white bags contain 1 shiny gold bag.
+muted yellow bags contain 3 faded blue bags.
+shiny gold bags contain 1 dark olive bag.
+dark olive bags contain 2 shiny gold bags.
+bright white bags contain 2 faded blue bags.
+faded blue bags contain no other bags.
+pale teal bags contain 1 posh tan bag.
+posh tan bags contain 1 pale teal bag, 1 clear cyan bag.
+wavy red bags contain 1 wavy red bag.".lines().map(|s| s.to_string()).collect();
+    let issues = BagRules::parse(&rules).validate();
+    assert_eq!(issues, vec![
+        RuleIssue::Cycle(vec!["dark olive".to_string(), "shiny gold".to_string()]),
+        RuleIssue::Cycle(vec!["pale teal".to_string(), "posh tan".to_string()]),
+        RuleIssue::Cycle(vec!["wavy red".to_string()]),
+        RuleIssue::DuplicateDefinition { bag: "bright white".to_string(), lines: vec![2, 6] },
+        RuleIssue::Undefined { bag: "clear cyan".to_string(), referenced_by: vec!["posh tan".to_string()] },
+        RuleIssue::Unreachable("clear cyan".to_string()),
+        RuleIssue::Unreachable("pale teal".to_string()),
+        RuleIssue::Unreachable("posh tan".to_string()),
+        RuleIssue::Unreachable("wavy red".to_string()),
+    ]);
+    assert_eq!(issues[3].to_string(), "bright white is defined on lines 2, 6");
+    assert_eq!(issues[4].to_string(), "clear cyan is never defined (used by posh tan)");
+}
+
+fn test_export_dot() {
+    let rules = BagRules::parse(&example_rules());
+    assert_eq!(rules.subgraph("shiny gold").to_dot(), "digraph bags {
+    \"shiny gold\";
+    \"dark olive\";
+    \"vibrant plum\";
+    \"dotted black\";
+    \"faded blue\";
+    \"shiny gold\" -> \"dark olive\" [label=\"1\"];
+    \"shiny gold\" -> \"vibrant plum\" [label=\"2\"];
+    \"dark olive\" -> \"dotted black\" [label=\"4\"];
+    \"dark olive\" -> \"faded blue\" [label=\"3\"];
+    \"vibrant plum\" -> \"dotted black\" [label=\"6\"];
+    \"vibrant plum\" -> \"faded blue\" [label=\"5\"];
+}
+");
+
+    let full = rules.export_from(None).to_dot();
+    assert_eq!(full.matches(" -> ").count(), 13);
+    assert_eq!(full.lines().count(), 2 + 9 + 13);
+    assert_eq!(rules.subgraph("no such").to_dot(), "digraph bags {\n}\n");
+
+    let mut quoted = BagRules::new();
+    quoted.add_rule("say \"hi\"", "plain", 1);
+    assert!(quoted.to_dot().contains("    \"say \\\"hi\\\"\" -> \"plain\" [label=\"1\"];\n"));
+}
+
+fn test_export_json() {
+    let rules = BagRules::parse(&example_rules());
+    let subgraph = rules.subgraph("shiny gold");
+    let restored = BagRules::from_json(&subgraph.to_json()).unwrap();
+    assert_eq!(restored.bags().count(), 5);
+    assert_eq!(restored.count_contained("shiny gold"), Ok(32));
+    assert_eq!(restored.contents("vibrant plum"), rules.contents("vibrant plum"));
+    assert_eq!(restored.containers("shiny gold"), vec![]);
+    assert_eq!(restored.validate(), vec![]);
+    assert_eq!(restored.to_json(), subgraph.to_json());
+
+    let full = BagRules::from_json(&rules.export_from(None).to_json()).unwrap();
+    assert_eq!(full.all_containers("shiny gold"), rules.all_containers("shiny gold"));
+
+    assert!(BagRules::from_json("{\"graph\": 3}").is_err());
+}
+
+fn test_parse_rule() {
+    assert_eq!(parse_rule("light red bags contain 1 bright white bag, 2 muted yellow bags."), Ok((
+        "light red".to_string(),
+        vec![(1, "bright white".to_string()), (2, "muted yellow".to_string())],
+    )));
+    assert_eq!(parse_rule("faded blue bags contain no other bags."), Ok(("faded blue".to_string(), vec![])));
+    assert_eq!(parse_rule("  shimmering  deep sea green bag contain 12 red bags ,  1 very pale lilac bag  "), Ok((
+        "shimmering deep sea green".to_string(),
+        vec![(12, "red".to_string()), (1, "very pale lilac".to_string())],
+    )));
+
+    assert_eq!(parse_rule("light red bags hold 1 bright white bag."), Err("missing \"contain\"".to_string()));
+    assert_eq!(parse_rule("bags contain 1 bright white bag."), Err("expected a colour before \"bags\"".to_string()));
+    assert_eq!(parse_rule("light red boxes contain 1 bright white bag."),
+        Err("expected \"bag\" or \"bags\" after \"light red boxes\"".to_string()));
+    assert_eq!(parse_rule("light red bags contain."), Err("nothing after \"contain\" for light red".to_string()));
+    assert_eq!(parse_rule("light red bags contain one bright white bag."),
+        Err("expected a quantity, found \"one\"".to_string()));
+    assert_eq!(parse_rule("light red bags contain 0 bright white bags."),
+        Err("quantity of bright white bags must be at least 1".to_string()));
+    assert_eq!(parse_rule("light red bags contain 1 bright white bag,, 2 tan bags."),
+        Err("empty item in contents".to_string()));
+    assert_eq!(parse_rule("light red bags contain 3 bags."), Err("expected a colour before \"bags\"".to_string()));
+    assert_eq!(parse_rule("light red bags contain 1 red bag, 2 red bags."), Err("duplicate inner bag red".to_string()));
+
+    let rules: Vec<String> = vec![
+        "light red bags contain 1 red bag, 2 red bags.".to_string(),
+        "red bags contain no other bags.".to_string(),
+    ];
+    let (bags, errors) = BagRules::parse_checked(&rules);
+    assert_eq!(errors.iter().map(|error| error.to_string()).collect::<Vec<String>>(),
+        vec!["line 1: duplicate inner bag red in \"light red bags contain 1 red bag, 2 red bags.\"".to_string()]);
+    assert_eq!(bags.node("light red"), None);
+}
+
+fn test_parse_checked() {
+    let rules: Vec<String> = "shiny gold bag contains 1 dark red bag.
+shiny gold bag contain 10 dark red bags, 200 very dark navy blue bags.
+
+dark red bags contain 2 dark orange bags
+dark orange bags contain no other bags.
+very dark navy blue bags contain no other bags.
+dark red bags contains 2 bags.".lines().map(|s| s.to_string()).collect();
+    let (bags, errors) = BagRules::parse_checked(&rules);
+
+    assert_eq!(errors, vec![
+        RuleParseError {
+            line: 1,
+            rule: "shiny gold bag contains 1 dark red bag.".to_string(),
+            reason: "missing \"contain\"".to_string(),
+        },
+        RuleParseError {
+            line: 7,
+            rule: "dark red bags contains 2 bags.".to_string(),
+            reason: "missing \"contain\"".to_string(),
+        },
+    ]);
+    assert_eq!(errors[0].to_string(), "line 1: missing \"contain\" in \"shiny gold bag contains 1 dark red bag.\"");
+    assert_eq!(bags.contents("shiny gold"), vec![
+        ("dark red".to_string(), 10),
+        ("very dark navy blue".to_string(), 200),
+    ]);
+    assert_eq!(bags.count_contained("shiny gold"), Ok(10 * 3 + 200));
+    assert_eq!(bags.definitions["dark red"], vec![4]);
+    assert_eq!(bags.validate(), vec![]);
+}
+
+fn test_shortest_path() {
+    let rules = BagRules::parse(&example_rules());
+    assert_eq!(rules.shortest_path("light red", "shiny gold"), Some(vec![
+        "light red".to_string(),
+        "bright white".to_string(),
+        "shiny gold".to_string(),
+    ]));
+    assert_eq!(rules.shortest_path("light red", "faded blue"), Some(vec![
+        "light red".to_string(),
+        "muted yellow".to_string(),
+        "faded blue".to_string(),
+    ]));
+    assert_eq!(rules.shortest_path("shiny gold", "shiny gold"), Some(vec!["shiny gold".to_string()]));
+    assert_eq!(rules.shortest_path("shiny gold", "light red"), None);
+    assert_eq!(rules.shortest_path("shiny gold", "no such"), None);
+}
+
+fn test_within_levels() {
+    let rules = BagRules::parse(&example_rules());
+    assert_eq!(rules.within_levels("shiny gold", 0), vec![]);
+    assert_eq!(rules.within_levels("shiny gold", 1), vec![
+        ("dark olive".to_string(), 1),
+        ("vibrant plum".to_string(), 1),
+    ]);
+    assert_eq!(rules.within_levels("light red", 2), vec![
+        ("bright white".to_string(), 1),
+        ("muted yellow".to_string(), 1),
+        ("faded blue".to_string(), 2),
+        ("shiny gold".to_string(), 2),
+    ]);
+    assert_eq!(rules.within_levels("light red", 10).len(), 7);
+}
+
+fn test_deepest_nesting() {
+    let rules = BagRules::parse(&example_rules());
+    assert_eq!(rules.deepest_nesting(), Ok(Some(("dark orange".to_string(), 4))));
+    assert_eq!(BagRules::new().deepest_nesting(), Ok(None));
+
+    let mut cyclic = BagRules::new();
+    cyclic.add_rule("a", "b", 1);
+    cyclic.add_rule("b", "a", 1);
+    assert!(cyclic.deepest_nesting().is_err());
+}
+
+fn test_contents_breakdown() {
+    let rules = BagRules::parse(&example_rules());
+    let breakdown = rules.contents_breakdown("shiny gold").unwrap();
+    assert_eq!(breakdown.into_iter().collect::<Vec<(String, u128)>>(), vec![
+        ("dark olive".to_string(), 1),
+        ("dotted black".to_string(), 4 + 2 * 6),
+        ("faded blue".to_string(), 3 + 2 * 5),
+        ("vibrant plum".to_string(), 2),
+    ]);
+
+    let breakdown = rules.contents_breakdown("light red").unwrap();
+    assert_eq!(breakdown.values().sum::<u128>(), rules.count_contained("light red").unwrap());
+    assert_eq!(breakdown["shiny gold"], 1 + 2 * 2);
+    assert_eq!(rules.contents_breakdown("faded blue").unwrap().len(), 0);
+    assert_eq!(rules.contents_breakdown("no such").unwrap().len(), 0);
+
+    assert_eq!(chain_rules(130, 2).contents_breakdown("bag 0"),
+        Err(CountError::Overflow { bag: "bag 127".to_string() }));
+}
+
+fn test_bag_query() {
+    let words = |line: &str| line.split('|').map(|word| word.to_string()).collect::<Vec<String>>();
+    assert_eq!(BagQuery::parse(&words("path|light red|shiny gold")), Ok(BagQuery::Path {
+        from: "light red".to_string(),
+        to: "shiny gold".to_string(),
+    }));
+    assert_eq!(BagQuery::parse(&words("within|shiny gold|2")), Ok(BagQuery::Within {
+        bag: "shiny gold".to_string(),
+        levels: 2,
+    }));
+    assert_eq!(BagQuery::parse(&words("deepest")), Ok(BagQuery::Deepest));
+    assert!(BagQuery::parse(&words("within|shiny gold|two")).is_err());
+    assert!(BagQuery::parse(&words("teleport")).is_err());
+
+    let rules = BagRules::parse(&example_rules());
+    let run = |line: &str| rules.run_query(&BagQuery::parse(&words(line)).unwrap()).unwrap();
+    assert_eq!(run("path|light red|shiny gold"), "light red -> bright white -> shiny gold\n");
+    assert_eq!(run("path|shiny gold|light red"), "shiny gold cannot hold light red\n");
+    assert_eq!(run("within|shiny gold|1"), "1 dark olive\n1 vibrant plum\n");
+    assert_eq!(run("deepest"), "dark orange nests 4 levels deep\n");
+    assert_eq!(run("breakdown|shiny gold"), "1 dark olive\n16 dotted black\n13 faded blue\n2 vibrant plum\ntotal 32\n");
+}
@@ -1,137 +1,1354 @@
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::sync::OnceLock;
 
 fn main() {
     test_calculate_accumulator();
+    test_parse_instruction();
+    test_parse_program();
+    test_run_outcomes();
+    test_repair();
+    test_trace();
+    test_debugger();
+    test_static_analysis();
+    test_analysis_output();
+    test_vm_boot_code();
+    test_vm_extended();
+    test_vm_custom_opcode();
 
-    let instructions = read_data("src/input.txt");
-    let acc = calculate_accumulator(&instructions);
+    let args: Vec<String> = env::args().collect();
+    let filename = args.get(2).map_or("src/input.txt", |filename| filename.as_str());
+    let program = match Program::load(filename) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}: {}", filename, e);
+            process::exit(1);
+        }
+    };
+    match args.get(1).map(|command| command.as_str()) {
+        Some("trace") => {
+            print_trace(&program);
+            return;
+        }
+        Some("debug") => {
+            run_debugger(&program);
+            return;
+        }
+        Some("analyze") => {
+            print!("{}", StaticAnalysis::new(&program).to_report(&program));
+            return;
+        }
+        Some("dot") => {
+            print!("{}", StaticAnalysis::new(&program).to_dot(&program));
+            return;
+        }
+        _ => {}
+    }
+
+    let acc = calculate_accumulator(&program);
     println!("looped acc {}", acc);
 
-    let working_instruction = find_working_instruction(&instructions);
-    let working_acc = calculate_accumulator(&working_instruction);
-    println!("working acc {}", working_acc);
+    match find_working_instruction_linear(&program) {
+        Some(repair) => {
+            println!("patched {}", repair);
+            println!("working acc {}", repair.result.accumulator);
+        }
+        None => println!("no single flip makes the program terminate"),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Instruction {
+    Acc(i64),
+    Jmp(i64),
+    Nop(i64),
+}
+
+impl Instruction {
+    /// Decodes one line such as `acc +1` or `jmp -3`.
+    pub fn parse(line: &str) -> Result<Instruction, String> {
+        let mut words = line.split_whitespace();
+        let opcode = words.next().unwrap_or("");
+        let argument = match opcode {
+            "acc" | "jmp" | "nop" => match words.next() {
+                Some(argument) => argument,
+                None => return Err(format!("missing argument for {}", opcode)),
+            },
+            _ => return Err(format!("unknown opcode {:?}", opcode)),
+        };
+        if let Some(extra) = words.next() {
+            return Err(format!("unexpected {:?} after argument", extra));
+        }
+        let argument: i64 = argument.parse()
+            .map_err(|_| format!("invalid argument {:?}", argument))?;
+
+        return Ok(match opcode {
+            "acc" => Instruction::Acc(argument),
+            "jmp" => Instruction::Jmp(argument),
+            _ => Instruction::Nop(argument),
+        });
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::Acc(argument) => write!(f, "acc {:+}", argument),
+            Instruction::Jmp(argument) => write!(f, "jmp {:+}", argument),
+            Instruction::Nop(argument) => write!(f, "nop {:+}", argument),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ProgramError {
+    line: usize,
+    text: String,
+    reason: String,
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {} in {:?}", self.line, self.reason, self.text)
+    }
+}
+
+impl std::error::Error for ProgramError {}
+
+/// Boot code decoded once up front, one instruction per source line.
+#[derive(Debug, Clone, PartialEq)]
+struct Program {
+    instructions: Vec<Instruction>,
+}
+
+impl Program {
+    pub fn new(instructions: Vec<Instruction>) -> Program {
+        Program { instructions }
+    }
+
+    /// Decodes every line, stopping at the first one that is not a valid
+    /// instruction. Line numbers in errors start at 1.
+    pub fn parse(source: &str) -> Result<Program, ProgramError> {
+        let mut instructions = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            let instruction = Instruction::parse(line).map_err(|reason| ProgramError {
+                line: index + 1,
+                text: line.to_string(),
+                reason,
+            })?;
+            instructions.push(instruction);
+        }
+
+        return Ok(Program { instructions });
+    }
+
+    pub fn load(filename: &str) -> Result<Program, Box<dyn std::error::Error>> {
+        let source = fs::read_to_string(filename)?;
+        return Ok(Program::parse(&source)?);
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Instruction> {
+        self.instructions.get(index).cloned()
+    }
 }
 
-fn read_data(filename: &str) -> Vec<String> {
-    let file = File::open(filename)
-    .expect("Something went wrong reading the file");
-    let reader = BufReader::new(file);
-    let passes = reader.lines().map(|line| line.unwrap()).collect();
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instruction in &self.instructions {
+            writeln!(f, "{}", instruction)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a boot code run stopped: the boot code view of `VmExit` and
+/// `VmError`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunOutcome {
+    /// Reached the instruction just past the end.
+    Terminated,
+    /// Was about to run instruction `at` a second time.
+    InfiniteLoop { at: usize },
+    /// A jump aimed before the first instruction or beyond the end.
+    OutOfBounds { target: i64 },
+    /// Ran the configured number of steps without stopping.
+    StepLimit,
+    /// An `acc` would take the accumulator past the range of an `i64`.
+    Overflow,
+}
 
-    return passes;
+impl From<VmExit> for RunOutcome {
+    fn from(exit: VmExit) -> RunOutcome {
+        match exit {
+            // Boot code has no `hlt`, so halting only happens at the end.
+            VmExit::Terminated | VmExit::Halted => RunOutcome::Terminated,
+            VmExit::Repeated { pc } => RunOutcome::InfiniteLoop { at: pc },
+            VmExit::StepLimit => RunOutcome::StepLimit,
+        }
+    }
+}
+
+impl From<VmError> for RunOutcome {
+    fn from(error: VmError) -> RunOutcome {
+        match error {
+            VmError::OutOfBounds { target, .. } => RunOutcome::OutOfBounds { target },
+            // The only boot code opcode that can fail is an overflowing `acc`.
+            VmError::Fault { .. } => RunOutcome::Overflow,
+        }
+    }
 }
 
-struct BootCode {
+/// How a run ended, with the machine state at that point. After an
+/// `OutOfBounds` jump or an `Overflow` the index pointer is still on the
+/// instruction that tried it, and the accumulator is unchanged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RunResult {
+    outcome: RunOutcome,
     accumulator: i64,
     index_pointer: usize,
-    executed_lines: HashSet<usize>,
+    steps: usize,
 }
 
-impl BootCode {
-    pub fn new() -> BootCode {
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunOutcome::Terminated => write!(f, "program terminated"),
+            RunOutcome::InfiniteLoop { at } => write!(f, "infinite loop: line {} would run again", at + 1),
+            RunOutcome::OutOfBounds { target } => write!(f, "jump to instruction {} is outside the program", target),
+            RunOutcome::StepLimit => write!(f, "step limit reached"),
+            RunOutcome::Overflow => write!(f, "accumulator overflow"),
+        }
+    }
+}
+
+/// One executed instruction. `index` counts from 0; it is shown as a
+/// line number from 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TraceStep {
+    index: usize,
+    instruction: Instruction,
+    accumulator_before: i64,
+    accumulator_after: i64,
+}
+
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {:>4}: {:<9} acc {} -> {}",
+            self.index + 1, self.instruction.to_string(), self.accumulator_before, self.accumulator_after)
+    }
+}
+
+/// The tail of a trace that forms the loop the run got stuck in, from the
+/// first time the repeated instruction ran.
+fn loop_cycle(trace: &[TraceStep], outcome: RunOutcome) -> Option<&[TraceStep]> {
+    if let RunOutcome::InfiniteLoop { at } = outcome {
+        let start = trace.iter().position(|step| step.index == at)?;
+        return Some(&trace[start..]);
+    }
+    return None;
+}
+
+fn format_loop_cycle(cycle: &[TraceStep]) -> String {
+    let gain: i64 = cycle.iter().map(|step| step.accumulator_after - step.accumulator_before).sum();
+    let mut report = format!("loop of {} instructions entered at line {}, acc {:+} per pass\n",
+        cycle.len(), cycle[0].index + 1, gain);
+    for step in cycle {
+        report.push_str(&format!("  {}\n", step));
+    }
+    return report;
+}
+
+/// The handheld: a program compiled once for the boot code instruction set
+/// and run on a `Vm`, stopping before any instruction runs twice.
+struct BootCode<'a> {
+    program: &'a Program,
+    compiled: Vec<VmInstruction>,
+    vm: Vm<'static>,
+    io: BufferedIo,
+}
+
+impl<'a> BootCode<'a> {
+    pub fn new(program: &'a Program) -> BootCode<'a> {
+        let set = InstructionSet::shared_boot_code();
         BootCode {
-            accumulator: 0,
-            index_pointer: 0,
-            executed_lines: HashSet::new(),
+            program,
+            compiled: set.compile(program).expect("the boot code set has every boot code instruction"),
+            vm: Vm::new(set).with_repeat_detection(),
+            io: BufferedIo::default(),
         }
     }
 
-    pub fn run_until_loop(&mut self, instructions: &Vec<String>) -> i64 {
+    pub fn with_step_limit(mut self, limit: usize) -> BootCode<'a> {
+        self.vm = self.vm.with_step_limit(limit);
+        return self;
+    }
+
+    pub fn accumulator(&self) -> i64 {
+        return self.vm.state.registers[0];
+    }
+
+    pub fn index_pointer(&self) -> usize {
+        return self.vm.state.pc;
+    }
+
+    pub fn steps(&self) -> usize {
+        return self.vm.steps;
+    }
+
+    /// Runs until the program ends, repeats an instruction, jumps out of
+    /// bounds, overflows or hits the step limit.
+    pub fn run(&mut self) -> RunResult {
+        let outcome = loop {
+            if let Err(outcome) = self.step() {
+                break outcome;
+            }
+        };
+        return self.result(outcome);
+    }
+
+    /// Like `run`, but also returns every instruction it executed.
+    pub fn trace(&mut self) -> (Vec<TraceStep>, RunResult) {
+        let mut trace = Vec::new();
         loop {
-            if self.executed_lines.contains(&self.index_pointer) 
-            || self.index_pointer == instructions.len() {
-                break;
+            match self.step() {
+                Ok(step) => trace.push(step),
+                Err(outcome) => return (trace, self.result(outcome)),
+            }
+        }
+    }
+
+    /// Executes one instruction, or says why the run cannot go on.
+    pub fn step(&mut self) -> Result<TraceStep, RunOutcome> {
+        let index = self.index_pointer();
+        let accumulator_before = self.accumulator();
+        if let Some(exit) = self.vm.step(&self.compiled, &mut self.io)? {
+            return Err(exit.into());
+        }
+
+        return Ok(TraceStep {
+            index,
+            instruction: self.program.instructions[index],
+            accumulator_before,
+            accumulator_after: self.accumulator(),
+        });
+    }
+
+    fn result(&self, outcome: RunOutcome) -> RunResult {
+        RunResult {
+            outcome,
+            accumulator: self.accumulator(),
+            index_pointer: self.index_pointer(),
+            steps: self.steps(),
+        }
+    }
+
+    pub fn run_until_loop(&mut self) -> i64 {
+        return self.run().accumulator;
+    }
+
+    /// Whether a fresh run ends in an infinite loop.
+    pub fn does_loop(&mut self) -> bool {
+        self.reset();
+        let outcome = self.run().outcome;
+        self.reset();
+        return matches!(outcome, RunOutcome::InfiniteLoop { .. });
+    }
+
+    /// Whether a fresh run steps cleanly off the end.
+    pub fn terminates(&mut self) -> bool {
+        self.reset();
+        let outcome = self.run().outcome;
+        self.reset();
+        return outcome == RunOutcome::Terminated;
+    }
+
+    pub fn reset(&mut self) {
+        self.vm.reset();
+    }
+}
+
+fn calculate_accumulator(program: &Program) -> i64 {
+    let mut code = BootCode::new(program);
+    return code.run_until_loop();
+}
+
+/// A single `jmp`/`nop` flip that lets the program terminate.
+#[derive(Debug, Clone, PartialEq)]
+struct Repair {
+    index: usize,
+    original: Instruction,
+    patched: Instruction,
+    result: RunResult,
+}
+
+impl Repair {
+    /// The program with the patch applied.
+    pub fn apply(&self, program: &Program) -> Program {
+        let mut patched = program.clone();
+        patched.instructions[self.index] = self.patched;
+        return patched;
+    }
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {} -> {}", self.index + 1, self.original, self.patched)
+    }
+}
+
+impl Instruction {
+    /// `jmp` and `nop` swapped, keeping the argument; `acc` has no flip.
+    pub fn flipped(&self) -> Option<Instruction> {
+        match *self {
+            Instruction::Acc(_) => None,
+            Instruction::Jmp(argument) => Some(Instruction::Nop(argument)),
+            Instruction::Nop(argument) => Some(Instruction::Jmp(argument)),
+        }
+    }
+}
+
+impl Program {
+    /// Where instruction `index` goes next if it is `instruction`, bounds
+    /// unchecked. A target beyond the range of an `i64` is clamped to it.
+    pub fn target(index: usize, instruction: Instruction) -> i64 {
+        match instruction {
+            Instruction::Jmp(argument) => (index as i64).saturating_add(argument),
+            _ => index as i64 + 1,
+        }
+    }
+
+    /// Where instruction `index` goes next if it is `instruction`: the
+    /// follower, a jump target, or `None` when the jump leaves `0..=len`.
+    pub fn successor(&self, index: usize, instruction: Instruction) -> Option<usize> {
+        let offset = match instruction {
+            Instruction::Jmp(argument) => argument,
+            _ => 1,
+        };
+        let target = (index as i64).checked_add(offset)?;
+        if target < 0 || target > self.len() as i64 {
+            return None;
+        }
+        return Some(target as usize);
+    }
+
+    /// The instructions that lead to each one, with index `len()` standing
+    /// for the end.
+    fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); self.len() + 1];
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            if let Some(target) = self.successor(index, *instruction) {
+                predecessors[target].push(index);
+            }
+        }
+        return predecessors;
+    }
+
+    /// Instructions in the order execution first reaches them, and how
+    /// execution ends; never `StepLimit` or `Overflow`. Control flow never
+    /// branches, so the run follows a single chain.
+    pub fn execution_path(&self) -> (Vec<usize>, RunOutcome) {
+        let mut path = Vec::new();
+        let mut visited = vec![false; self.len()];
+        let mut index = 0;
+        let outcome = loop {
+            if index == self.len() {
+                break RunOutcome::Terminated;
             }
-            self.executed_lines.insert(self.index_pointer);
-            let instruction = &instructions[self.index_pointer];
-            if instruction.starts_with("acc") {
-                self.acc(&instruction);
+            if visited[index] {
+                break RunOutcome::InfiniteLoop { at: index };
             }
-            else if instruction.starts_with("jmp") {
-                self.jmp(&instruction);
+            visited[index] = true;
+            path.push(index);
+            index = match self.successor(index, self.instructions[index]) {
+                Some(next) => next,
+                None => break RunOutcome::OutOfBounds {
+                    target: Program::target(index, self.instructions[index]),
+                },
+            };
+        };
+        return (path, outcome);
+    }
+
+    /// Which instructions eventually step off the end, found by walking
+    /// jumps backwards from the end. Index `len()` stands for the end itself.
+    pub fn terminating_instructions(&self) -> Vec<bool> {
+        let predecessors = self.predecessors();
+        let mut terminating = vec![false; self.len() + 1];
+        terminating[self.len()] = true;
+        let mut stack = vec![self.len()];
+        while let Some(index) = stack.pop() {
+            for &predecessor in &predecessors[index] {
+                if !terminating[predecessor] {
+                    terminating[predecessor] = true;
+                    stack.push(predecessor);
+                }
             }
-            else {
-                self.index_pointer += 1;
+        }
+        return terminating;
+    }
+
+    /// Instructions on the execution path whose `jmp`/`nop` flip makes the
+    /// program terminate, in path order. A flip works when its new successor
+    /// reaches the end without coming back through the flipped instruction,
+    /// and a route to the end can only do that by joining the path at or
+    /// before it. One backwards walk from the end finds where every route
+    /// joins the path, so this is linear.
+    pub fn terminating_flips(&self) -> Vec<usize> {
+        let (path, _) = self.execution_path();
+        let mut position = vec![None; self.len() + 1];
+        for (step, &index) in path.iter().enumerate() {
+            position[index] = Some(step);
+        }
+
+        // The path position where the route from each instruction to the
+        // end first meets the path, with the end itself counting as last.
+        // Routes to the end never branch back, so each is seen once.
+        let predecessors = self.predecessors();
+        let mut joins: Vec<Option<usize>> = vec![None; self.len() + 1];
+        joins[self.len()] = Some(path.len());
+        let mut stack = vec![self.len()];
+        while let Some(index) = stack.pop() {
+            for &predecessor in &predecessors[index] {
+                joins[predecessor] = position[predecessor].or(joins[index]);
+                stack.push(predecessor);
             }
         }
 
-        return self.accumulator;
+        return path.iter().enumerate()
+            .filter(|&(step, &index)| {
+                self.instructions[index].flipped()
+                    .and_then(|flipped| self.successor(index, flipped))
+                    .and_then(|next| joins[next])
+                    .is_some_and(|join| join > step)
+            })
+            .map(|(_, &index)| index)
+            .collect();
     }
+}
 
-    pub fn does_loop(&mut self, instructions: &Vec<String>) -> bool {
-        self.run_until_loop(instructions);
-        let does_loop = self.index_pointer == instructions.len();
-        self.reset();
-        return does_loop;
+/// Tries flipping each `jmp`/`nop` in turn, first line first, and runs the
+/// patched program. Quadratic in the worst case.
+fn find_working_instruction(program: &Program) -> Option<Repair> {
+    let mut patched = program.clone();
+    for (index, &original) in program.instructions.iter().enumerate() {
+        let flipped = match original.flipped() {
+            Some(flipped) => flipped,
+            None => continue,
+        };
+        patched.instructions[index] = flipped;
+        let result = BootCode::new(&patched).run();
+        patched.instructions[index] = original;
+        if result.outcome == RunOutcome::Terminated {
+            return Some(Repair { index, original, patched: flipped, result });
+        }
     }
+    return None;
+}
 
-    pub fn acc(&mut self, acc_instruction: &str) {
-        let get_acc_count = |instruction| get_number_from_instruction("acc", instruction);
-        self.accumulator += get_acc_count(acc_instruction);
-        self.index_pointer += 1;
+/// Finds the same kind of repair in linear time, trying only the flips
+/// that `Program::terminating_flips` finds, first line first. A flip is
+/// still run to check that no `acc` overflows on the way to the end.
+fn find_working_instruction_linear(program: &Program) -> Option<Repair> {
+    let mut flips = program.terminating_flips();
+    flips.sort();
+    let mut patched = program.clone();
+    for index in flips {
+        let original = program.instructions[index];
+        let flipped = original.flipped().unwrap();
+        patched.instructions[index] = flipped;
+        let result = BootCode::new(&patched).run();
+        patched.instructions[index] = original;
+        if result.outcome == RunOutcome::Terminated {
+            return Some(Repair { index, original, patched: flipped, result });
+        }
     }
+    return None;
+}
 
-    pub fn jmp(&mut self, jmp_instruction: &str) {
-        let get_jmp_count = |instruction| get_number_from_instruction("jmp", instruction);
-        self.index_pointer = (self.index_pointer as i64 + get_jmp_count(jmp_instruction)) as usize;
+
+/// What can be said about a program from its jumps alone, without
+/// executing any `acc`. Indexes count from 0; reports show lines from 1.
+#[derive(Debug, PartialEq)]
+struct StaticAnalysis {
+    /// Each instruction's successor, `None` when it jumps out of bounds.
+    successors: Vec<Option<usize>>,
+    /// Instructions in the order execution first reaches them.
+    path: Vec<usize>,
+    /// How execution ends; never `StepLimit` or `Overflow`.
+    outcome: RunOutcome,
+    /// Instructions that the run passes through forever, when it loops.
+    cycle: Vec<usize>,
+    /// Instructions on the path whose `jmp`/`nop` flip makes it terminate.
+    terminating_flips: Vec<usize>,
+}
+
+impl StaticAnalysis {
+    pub fn new(program: &Program) -> StaticAnalysis {
+        let successors: Vec<Option<usize>> = program.instructions.iter().enumerate()
+            .map(|(index, &instruction)| program.successor(index, instruction))
+            .collect();
+        let (path, outcome) = program.execution_path();
+        let cycle = match outcome {
+            RunOutcome::InfiniteLoop { at } => path[path.iter().position(|&index| index == at).unwrap()..].to_vec(),
+            _ => Vec::new(),
+        };
+        let terminating_flips = program.terminating_flips();
+
+        return StaticAnalysis { successors, path, outcome, cycle, terminating_flips };
     }
 
-    pub fn reset(&mut self) {
-        self.index_pointer = 0;
-        self.executed_lines.clear();
+    pub fn unreachable(&self) -> Vec<usize> {
+        let mut reachable = vec![false; self.successors.len()];
+        for &index in &self.path {
+            reachable[index] = true;
+        }
+        return (0..reachable.len()).filter(|&index| !reachable[index]).collect();
     }
-}
 
-fn get_number_from_instruction(command: &str, instruction: &str) -> i64 {
-    let mut number = instruction.strip_prefix(command).unwrap().trim_start();
-    if number.starts_with("+") {
-        number = number.strip_prefix("+").unwrap();
+    pub fn to_report(&self, program: &Program) -> String {
+        let unreachable = self.unreachable();
+        let mut report = String::new();
+        report.push_str(&format!("instructions {}\n", program.len()));
+        report.push_str(&format!("reachable {}\n", self.path.len()));
+        report.push_str(&format!("unreachable {}: {}\n", unreachable.len(), line_ranges(&unreachable)));
+        match self.outcome {
+            RunOutcome::InfiniteLoop { at } => report.push_str(&format!(
+                "infinite loop of {} instructions entered at line {}: {}\n",
+                self.cycle.len(), at + 1, line_ranges(&self.cycle))),
+            outcome => report.push_str(&format!("{}\n", outcome)),
+        }
+        report.push_str(&format!("terminating flips {}\n", self.terminating_flips.len()));
+        for &index in &self.terminating_flips {
+            let instruction = program.instructions[index];
+            report.push_str(&format!("  line {}: {} -> {}\n", index + 1, instruction, instruction.flipped().unwrap()));
+        }
+        return report;
     }
 
-    return number.parse().unwrap_or(0);
+    /// Instructions as nodes with their successor edges. Unreachable
+    /// instructions are grey, the loop is red and terminating flips are
+    /// dashed green edges.
+    pub fn to_dot(&self, program: &Program) -> String {
+        let mut on_path = vec![false; program.len()];
+        for &index in &self.path {
+            on_path[index] = true;
+        }
+        let mut in_cycle = vec![false; program.len()];
+        for &index in &self.cycle {
+            in_cycle[index] = true;
+        }
+
+        let mut dot = String::from("digraph {\n");
+        dot.push_str("    start [shape=point];\n    end [shape=doublecircle];\n    out [shape=box, label=\"out of bounds\"];\n");
+        if !program.instructions.is_empty() {
+            dot.push_str("    start -> n0;\n");
+        } else {
+            dot.push_str("    start -> end;\n");
+        }
+        for (index, instruction) in program.instructions.iter().enumerate() {
+            let style = if in_cycle[index] {
+                ", color=red"
+            } else if !on_path[index] {
+                ", color=grey, fontcolor=grey"
+            } else {
+                ""
+            };
+            dot.push_str(&format!("    n{} [label=\"{}: {}\"{}];\n", index, index + 1, instruction, style));
+        }
+        let node = |successor: Option<usize>| match successor {
+            Some(next) if next == program.len() => "end".to_string(),
+            Some(next) => format!("n{}", next),
+            None => "out".to_string(),
+        };
+        for (index, &successor) in self.successors.iter().enumerate() {
+            dot.push_str(&format!("    n{} -> {};\n", index, node(successor)));
+        }
+        for &index in &self.terminating_flips {
+            let flipped = program.instructions[index].flipped().unwrap();
+            dot.push_str(&format!("    n{} -> {} [style=dashed, color=green, label=\"{}\"];\n",
+                index, node(program.successor(index, flipped)), flipped));
+        }
+        dot.push_str("}\n");
+        return dot;
+    }
 }
 
+/// Lines from 1 with runs collapsed, e.g. `3-5, 9`.
+fn line_ranges(indexes: &[usize]) -> String {
+    let mut sorted = indexes.to_vec();
+    sorted.sort();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for index in sorted {
+        match ranges.last_mut() {
+            Some(range) if range.1 + 1 == index => range.1 = index,
+            _ => ranges.push((index, index)),
+        }
+    }
+    return ranges.iter()
+        .map(|&(first, last)| match first == last {
+            true => format!("{}", first + 1),
+            false => format!("{}-{}", first + 1, last + 1),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+}
 
-fn calculate_accumulator(instructions: &Vec<String>) -> i64 {
-    let mut code = BootCode::new();
-    return code.run_until_loop(instructions);
+/// Steps through a program under user control. Commands take and show
+/// line numbers counting from 1.
+struct Debugger<'a> {
+    program: &'a Program,
+    code: BootCode<'a>,
+    breakpoints: BTreeSet<usize>,
+    trace: Vec<TraceStep>,
+    stopped: Option<RunOutcome>,
+    /// Set by `quit`; the caller should stop reading commands.
+    quit: bool,
 }
 
-fn find_working_instruction(instructions: &Vec<String>) -> Vec<String> {
-    let mut code = BootCode::new();
-    let mut last_jmp_changed = 0;
-    let mut last_nop_changed = 0;
-    let final_instructions: Vec<String>;
-    loop {
-        let mut loop_instructions = instructions.clone();
-        for (index, instruction) in loop_instructions.iter().enumerate() {
-            if instruction.starts_with("jmp") && index > last_jmp_changed {
-                loop_instructions[index] = "nop".to_string();
-                last_jmp_changed = index;
-                break;
+const DEBUGGER_HELP: &str = "commands:
+  s, step [n]      run n instructions (default 1)
+  c, continue      run to the next breakpoint or until the program stops
+  b, break <line>  set a breakpoint
+  d, delete <line> remove a breakpoint
+  p, print         show the current line, accumulator and step count
+  l, list [n]      show n lines either side of the current one (default 3)
+  loop             show the loop cycle once one has been detected
+  r, restart       start over, keeping breakpoints
+  q, quit          leave the debugger
+";
+
+impl<'a> Debugger<'a> {
+    pub fn new(program: &'a Program) -> Debugger<'a> {
+        Debugger {
+            program,
+            code: BootCode::new(program),
+            breakpoints: BTreeSet::new(),
+            trace: Vec::new(),
+            stopped: None,
+            quit: false,
+        }
+    }
+
+    pub fn has_quit(&self) -> bool {
+        return self.quit;
+    }
+
+    fn advance(&mut self) -> Result<TraceStep, RunOutcome> {
+        if let Some(outcome) = self.stopped {
+            return Err(outcome);
+        }
+        match self.code.step() {
+            Ok(step) => {
+                self.trace.push(step);
+                Ok(step)
             }
-            else if instruction.starts_with("nop") && index > last_nop_changed {
-                loop_instructions[index] = "jmp".to_string();
-                last_nop_changed = index;
-                break;
+            Err(outcome) => {
+                self.stopped = Some(outcome);
+                Err(outcome)
             }
         }
-        if code.does_loop(&loop_instructions) {
-            final_instructions = loop_instructions;
+    }
+
+    fn line_argument(&self, argument: Option<&str>) -> Result<usize, String> {
+        let line: usize = argument.and_then(|argument| argument.parse().ok())
+            .ok_or("expected a line number")?;
+        if line == 0 || line > self.program.len() {
+            return Err(format!("line {} is outside the program (1-{})", line, self.program.len()));
+        }
+        return Ok(line - 1);
+    }
+
+    /// Runs one command line and returns what to show the user.
+    pub fn command(&mut self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+        let result = match command {
+            "s" | "step" => self.step_command(argument),
+            "c" | "continue" => Ok(self.continue_command()),
+            "b" | "break" => self.line_argument(argument).map(|index| {
+                self.breakpoints.insert(index);
+                format!("breakpoint at line {}\n", index + 1)
+            }),
+            "d" | "delete" => self.line_argument(argument).map(|index| {
+                match self.breakpoints.remove(&index) {
+                    true => format!("removed breakpoint at line {}\n", index + 1),
+                    false => format!("no breakpoint at line {}\n", index + 1),
+                }
+            }),
+            "p" | "print" => Ok(self.state()),
+            "l" | "list" => argument.map_or(Ok(3), |argument| argument.parse().map_err(|_| "expected a line count".to_string()))
+                .map(|context| self.listing(context)),
+            "loop" => Ok(match self.stopped.and_then(|outcome| loop_cycle(&self.trace, outcome)) {
+                Some(cycle) => format_loop_cycle(cycle),
+                None => "no loop detected\n".to_string(),
+            }),
+            "r" | "restart" => {
+                self.code.reset();
+                self.trace.clear();
+                self.stopped = None;
+                Ok(self.state())
+            }
+            "q" | "quit" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            "h" | "help" | "" => Ok(DEBUGGER_HELP.to_string()),
+            _ => Err(format!("unknown command {:?}, try help", command)),
+        };
+
+        return match result {
+            Ok(output) => output,
+            Err(e) => format!("{}\n", e),
+        };
+    }
+
+    fn step_command(&mut self, argument: Option<&str>) -> Result<String, String> {
+        let count: usize = argument.map_or(Ok(1), |argument| argument.parse())
+            .map_err(|_| "expected a step count".to_string())?;
+        let mut output = String::new();
+        for _ in 0..count {
+            match self.advance() {
+                Ok(step) => output.push_str(&format!("{}\n", step)),
+                Err(outcome) => {
+                    output.push_str(&format!("{}\n", outcome));
+                    break;
+                }
+            }
+        }
+        return Ok(output);
+    }
+
+    fn continue_command(&mut self) -> String {
+        loop {
+            if let Err(outcome) = self.advance() {
+                return format!("{}\n", outcome);
+            }
+            if self.breakpoints.contains(&self.code.index_pointer()) {
+                return format!("breakpoint at line {}\n{}", self.code.index_pointer() + 1, self.state());
+            }
+        }
+    }
+
+    fn state(&self) -> String {
+        let at = match self.program.get(self.code.index_pointer()) {
+            Some(instruction) => format!("line {}: {}", self.code.index_pointer() + 1, instruction),
+            None => "end of program".to_string(),
+        };
+        return format!("{}  acc {}  steps {}\n", at, self.code.accumulator(), self.code.steps());
+    }
+
+    fn listing(&self, context: usize) -> String {
+        let pc = self.code.index_pointer();
+        let first = pc.saturating_sub(context);
+        let last = (pc + context + 1).min(self.program.len());
+        let mut output = String::new();
+        for index in first..last {
+            output.push_str(&format!("{}{} {:>4}  {}\n",
+                if index == pc { ">" } else { " " },
+                if self.breakpoints.contains(&index) { "*" } else { " " },
+                index + 1,
+                self.program.instructions[index]));
+        }
+        return output;
+    }
+}
+
+fn print_trace(program: &Program) {
+    let (trace, result) = BootCode::new(program).trace();
+    for step in &trace {
+        println!("{}", step);
+    }
+    println!("{} after {} steps, acc {}", result.outcome, result.steps, result.accumulator);
+    if let Some(cycle) = loop_cycle(&trace, result.outcome) {
+        print!("{}", format_loop_cycle(cycle));
+    }
+}
+
+fn run_debugger(program: &Program) {
+    let mut debugger = Debugger::new(program);
+    print!("{}", DEBUGGER_HELP);
+    let stdin = io::stdin();
+    loop {
+        print!("(boot) ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        print!("{}", debugger.command(&line));
+        if debugger.has_quit() {
             break;
         }
-        else {
+    }
+}
+
 
+/// A value an opcode reads or writes: a register picked by name at
+/// assembly time, or a signed literal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    Register(usize),
+    Immediate(i64),
+}
+
+/// Where execution goes after an opcode runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flow {
+    Next,
+    Jump(i64),
+    Halt,
+}
+
+/// Input and output hooks for opcodes that talk to the outside world.
+trait VmIo {
+    fn input(&mut self) -> Option<i64>;
+    fn output(&mut self, value: i64);
+}
+
+/// Feeds input from a queue and collects output, which is all the tests and
+/// most puzzles need.
+#[derive(Debug, Default)]
+struct BufferedIo {
+    input: VecDeque<i64>,
+    output: Vec<i64>,
+}
+
+impl BufferedIo {
+    pub fn new(input: Vec<i64>) -> BufferedIo {
+        BufferedIo { input: input.into_iter().collect(), output: Vec::new() }
+    }
+}
+
+impl VmIo for BufferedIo {
+    fn input(&mut self) -> Option<i64> {
+        return self.input.pop_front();
+    }
+
+    fn output(&mut self, value: i64) {
+        self.output.push(value);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct VmState {
+    pc: usize,
+    registers: Vec<i64>,
+}
+
+impl VmState {
+    pub fn read(&self, operand: Operand) -> i64 {
+        match operand {
+            Operand::Register(register) => self.registers[register],
+            Operand::Immediate(value) => value,
         }
     }
-    return final_instructions.to_vec();
+
+    pub fn write(&mut self, operand: Operand, value: i64) -> Result<(), String> {
+        match operand {
+            Operand::Register(register) => self.registers[register] = value,
+            Operand::Immediate(literal) => return Err(format!("cannot write to literal {:+}", literal)),
+        }
+        return Ok(());
+    }
+
+    /// Reads `target`, combines it with `value` and writes the result back,
+    /// failing rather than wrapping on overflow.
+    pub fn update(&mut self, target: Operand, value: i64, combine: fn(i64, i64) -> Option<i64>) -> Result<(), String> {
+        let result = combine(self.read(target), value).ok_or("arithmetic overflow")?;
+        return self.write(target, result);
+    }
 }
 
+/// One opcode of a VM instruction set. Implement this and pass it to
+/// `InstructionSet::register` to teach the VM a new instruction. Sets can be
+/// shared between threads, so opcodes must be too.
+trait Opcode: Send + Sync {
+    fn name(&self) -> &str;
+    fn arity(&self) -> usize;
+    fn execute(&self, operands: &[Operand], state: &mut VmState, io: &mut dyn VmIo) -> Result<Flow, String>;
+}
 
-fn test_calculate_accumulator() {
-    let instructions = "nop +0
+/// `acc v`: adds `v` to the first register.
+struct AccOpcode;
+
+impl Opcode for AccOpcode {
+    fn name(&self) -> &str { return "acc"; }
+    fn arity(&self) -> usize { return 1; }
+    fn execute(&self, operands: &[Operand], state: &mut VmState, _io: &mut dyn VmIo) -> Result<Flow, String> {
+        state.update(Operand::Register(0), state.read(operands[0]), i64::checked_add)?;
+        return Ok(Flow::Next);
+    }
+}
+
+/// `jmp v`: jumps `v` instructions away.
+struct JmpOpcode;
+
+impl Opcode for JmpOpcode {
+    fn name(&self) -> &str { return "jmp"; }
+    fn arity(&self) -> usize { return 1; }
+    fn execute(&self, operands: &[Operand], state: &mut VmState, _io: &mut dyn VmIo) -> Result<Flow, String> {
+        return Ok(Flow::Jump(state.read(operands[0])));
+    }
+}
+
+/// `nop v`: does nothing with its operand.
+struct NopOpcode;
+
+impl Opcode for NopOpcode {
+    fn name(&self) -> &str { return "nop"; }
+    fn arity(&self) -> usize { return 1; }
+    fn execute(&self, _operands: &[Operand], _state: &mut VmState, _io: &mut dyn VmIo) -> Result<Flow, String> {
+        return Ok(Flow::Next);
+    }
+}
+
+/// `name r v`: stores `op(r, v)` in register `r`, e.g. `set`, `add`, `mul`.
+struct ArithmeticOpcode {
+    name: &'static str,
+    op: fn(i64, i64) -> Option<i64>,
+}
+
+impl Opcode for ArithmeticOpcode {
+    fn name(&self) -> &str { return self.name; }
+    fn arity(&self) -> usize { return 2; }
+    fn execute(&self, operands: &[Operand], state: &mut VmState, _io: &mut dyn VmIo) -> Result<Flow, String> {
+        state.update(operands[0], state.read(operands[1]), self.op)?;
+        return Ok(Flow::Next);
+    }
+}
+
+/// `name v offset`: jumps `offset` away when `condition(v)` holds, e.g. `jz`, `jnz`.
+struct ConditionalJumpOpcode {
+    name: &'static str,
+    condition: fn(i64) -> bool,
+}
+
+impl Opcode for ConditionalJumpOpcode {
+    fn name(&self) -> &str { return self.name; }
+    fn arity(&self) -> usize { return 2; }
+    fn execute(&self, operands: &[Operand], state: &mut VmState, _io: &mut dyn VmIo) -> Result<Flow, String> {
+        if (self.condition)(state.read(operands[0])) {
+            return Ok(Flow::Jump(state.read(operands[1])));
+        }
+        return Ok(Flow::Next);
+    }
+}
+
+/// `in r`: reads the next input value into register `r`.
+struct InputOpcode;
+
+impl Opcode for InputOpcode {
+    fn name(&self) -> &str { return "in"; }
+    fn arity(&self) -> usize { return 1; }
+    fn execute(&self, operands: &[Operand], state: &mut VmState, io: &mut dyn VmIo) -> Result<Flow, String> {
+        let value = io.input().ok_or("input exhausted")?;
+        state.write(operands[0], value)?;
+        return Ok(Flow::Next);
+    }
+}
+
+/// `out v`: sends `v` to the output hook.
+struct OutputOpcode;
+
+impl Opcode for OutputOpcode {
+    fn name(&self) -> &str { return "out"; }
+    fn arity(&self) -> usize { return 1; }
+    fn execute(&self, operands: &[Operand], state: &mut VmState, io: &mut dyn VmIo) -> Result<Flow, String> {
+        io.output(state.read(operands[0]));
+        return Ok(Flow::Next);
+    }
+}
+
+/// `hlt`: stops the machine.
+struct HaltOpcode;
+
+impl Opcode for HaltOpcode {
+    fn name(&self) -> &str { return "hlt"; }
+    fn arity(&self) -> usize { return 0; }
+    fn execute(&self, _operands: &[Operand], _state: &mut VmState, _io: &mut dyn VmIo) -> Result<Flow, String> {
+        return Ok(Flow::Halt);
+    }
+}
+
+/// An assembled instruction: which opcode of its set, and its operands.
+#[derive(Debug, Clone, PartialEq)]
+struct VmInstruction {
+    opcode: usize,
+    operands: Vec<Operand>,
+}
+
+/// The opcodes and register names a VM understands.
+struct InstructionSet {
+    opcodes: Vec<Box<dyn Opcode>>,
+    registers: Vec<String>,
+}
+
+impl InstructionSet {
+    pub fn new(registers: &[&str]) -> InstructionSet {
+        InstructionSet {
+            opcodes: Vec::new(),
+            registers: registers.iter().map(|register| register.to_string()).collect(),
+        }
+    }
+
+    /// The handheld's own set: `acc`, `jmp` and `nop` over a single `acc` register.
+    pub fn boot_code() -> InstructionSet {
+        return InstructionSet::new(&["acc"])
+            .register(Box::new(AccOpcode))
+            .register(Box::new(JmpOpcode))
+            .register(Box::new(NopOpcode));
+    }
+
+    /// One `boot_code()` set, built on first use, for machines that outlive
+    /// any set of their own.
+    pub fn shared_boot_code() -> &'static InstructionSet {
+        static BOOT_CODE: OnceLock<InstructionSet> = OnceLock::new();
+        return BOOT_CODE.get_or_init(InstructionSet::boot_code);
+    }
+
+    /// The boot code set plus registers `a` to `d` and the arithmetic,
+    /// conditional jump, I/O and halt opcodes.
+    pub fn extended() -> InstructionSet {
+        let mut set = InstructionSet::boot_code();
+        for register in &["a", "b", "c", "d"] {
+            set.registers.push(register.to_string());
+        }
+        return set.register(Box::new(ArithmeticOpcode { name: "set", op: |_, value| Some(value) }))
+            .register(Box::new(ArithmeticOpcode { name: "add", op: i64::checked_add }))
+            .register(Box::new(ArithmeticOpcode { name: "mul", op: i64::checked_mul }))
+            .register(Box::new(ConditionalJumpOpcode { name: "jz", condition: |value| value == 0 }))
+            .register(Box::new(ConditionalJumpOpcode { name: "jnz", condition: |value| value != 0 }))
+            .register(Box::new(InputOpcode))
+            .register(Box::new(OutputOpcode))
+            .register(Box::new(HaltOpcode));
+    }
+
+    /// Adds an opcode, replacing any earlier one with the same name.
+    pub fn register(mut self, opcode: Box<dyn Opcode>) -> InstructionSet {
+        match self.opcode(opcode.name()) {
+            Some(index) => self.opcodes[index] = opcode,
+            None => self.opcodes.push(opcode),
+        }
+        return self;
+    }
+
+    fn opcode(&self, name: &str) -> Option<usize> {
+        return self.opcodes.iter().position(|opcode| opcode.name() == name);
+    }
+
+    fn known_opcode(&self, name: &str) -> Result<usize, String> {
+        return self.opcode(name).ok_or_else(|| format!("unknown opcode {:?}", name));
+    }
+
+    fn operand(&self, word: &str) -> Result<Operand, String> {
+        if let Some(register) = self.registers.iter().position(|register| register == word) {
+            return Ok(Operand::Register(register));
+        }
+        return word.parse()
+            .map(Operand::Immediate)
+            .map_err(|_| format!("invalid operand {:?}", word));
+    }
+
+    /// Checks that `opcode` takes this many operands.
+    fn instruction(&self, opcode: usize, operands: Vec<Operand>) -> Result<VmInstruction, String> {
+        let arity = self.opcodes[opcode].arity();
+        if operands.len() != arity {
+            return Err(format!("{} takes {} operands, found {}", self.opcodes[opcode].name(), arity, operands.len()));
+        }
+        return Ok(VmInstruction { opcode, operands });
+    }
+
+    /// Assembles source text, one instruction per line, checking opcode
+    /// names and operand counts. Line numbers in errors start at 1.
+    pub fn assemble(&self, source: &str) -> Result<Vec<VmInstruction>, ProgramError> {
+        let mut instructions = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            let instruction = self.assemble_line(line).map_err(|reason| ProgramError {
+                line: index + 1,
+                text: line.to_string(),
+                reason,
+            })?;
+            instructions.push(instruction);
+        }
+        return Ok(instructions);
+    }
+
+    fn assemble_line(&self, line: &str) -> Result<VmInstruction, String> {
+        let mut words = line.split_whitespace();
+        let opcode = self.known_opcode(words.next().unwrap_or(""))?;
+        let operands = words.map(|word| self.operand(word)).collect::<Result<Vec<Operand>, String>>()?;
+        return self.instruction(opcode, operands);
+    }
+
+    /// One boot code instruction for this set, its argument a literal.
+    fn compile_instruction(&self, instruction: Instruction) -> Result<VmInstruction, String> {
+        let (name, argument) = match instruction {
+            Instruction::Acc(argument) => ("acc", argument),
+            Instruction::Jmp(argument) => ("jmp", argument),
+            Instruction::Nop(argument) => ("nop", argument),
+        };
+        return self.instruction(self.known_opcode(name)?, vec![Operand::Immediate(argument)]);
+    }
+
+    /// The same program for this set, so boot code decoded by `Program`
+    /// runs unchanged on the VM.
+    pub fn compile(&self, program: &Program) -> Result<Vec<VmInstruction>, ProgramError> {
+        let mut instructions = Vec::new();
+        for (index, &instruction) in program.instructions.iter().enumerate() {
+            let compiled = self.compile_instruction(instruction).map_err(|reason| ProgramError {
+                line: index + 1,
+                text: instruction.to_string(),
+                reason,
+            })?;
+            instructions.push(compiled);
+        }
+        return Ok(instructions);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum VmExit {
+    /// Stepped to the instruction just past the end.
+    Terminated,
+    /// A `Flow::Halt` opcode ran.
+    Halted,
+    /// About to run an instruction a second time, with repeat detection on.
+    Repeated { pc: usize },
+    StepLimit,
+}
+
+/// Why the VM could not go on. After either error `pc` is still on the
+/// failing instruction, which counts as a step. A jump target beyond the
+/// range of an `i64` is reported as the nearest `i64`.
+#[derive(Debug, PartialEq)]
+enum VmError {
+    OutOfBounds { pc: usize, target: i64 },
+    Fault { pc: usize, reason: String },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::OutOfBounds { pc, target } => write!(f, "instruction {} jumps to {}, outside the program", pc, target),
+            VmError::Fault { pc, reason } => write!(f, "instruction {}: {}", pc, reason),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+struct Vm<'a> {
+    set: &'a InstructionSet,
+    state: VmState,
+    steps: usize,
+    step_limit: Option<usize>,
+    stop_on_repeat: bool,
+    executed: HashSet<usize>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(set: &'a InstructionSet) -> Vm<'a> {
+        Vm {
+            set,
+            state: VmState { pc: 0, registers: vec![0; set.registers.len()] },
+            steps: 0,
+            step_limit: None,
+            stop_on_repeat: false,
+            executed: HashSet::new(),
+        }
+    }
+
+    pub fn with_step_limit(mut self, limit: usize) -> Vm<'a> {
+        self.step_limit = Some(limit);
+        return self;
+    }
+
+    /// Stops before any instruction runs twice, which is how the handheld
+    /// spots its infinite loop.
+    pub fn with_repeat_detection(mut self) -> Vm<'a> {
+        self.stop_on_repeat = true;
+        return self;
+    }
+
+    pub fn register(&self, name: &str) -> Option<i64> {
+        let index = self.set.registers.iter().position(|register| register == name)?;
+        return Some(self.state.registers[index]);
+    }
+
+    /// Back to the first instruction with every register cleared.
+    pub fn reset(&mut self) {
+        self.state.pc = 0;
+        self.state.registers.iter_mut().for_each(|register| *register = 0);
+        self.steps = 0;
+        self.executed.clear();
+    }
+
+    /// Why the instruction at `pc` of a `len` instruction program should not
+    /// run, if there is a reason.
+    fn stop_reason(&self, len: usize) -> Option<VmExit> {
+        if self.state.pc == len {
+            return Some(VmExit::Terminated);
+        }
+        if self.stop_on_repeat && self.executed.contains(&self.state.pc) {
+            return Some(VmExit::Repeated { pc: self.state.pc });
+        }
+        if self.step_limit.is_some_and(|limit| self.steps >= limit) {
+            return Some(VmExit::StepLimit);
+        }
+        return None;
+    }
+
+    /// Runs `instruction` as the one at `pc` of a `len` instruction program,
+    /// without checking `stop_reason` first. Returns an exit only on a halt.
+    fn execute(&mut self, instruction: &VmInstruction, len: usize, io: &mut dyn VmIo) -> Result<Option<VmExit>, VmError> {
+        let pc = self.state.pc;
+        self.executed.insert(pc);
+        self.steps += 1;
+        let flow = self.set.opcodes[instruction.opcode]
+            .execute(&instruction.operands, &mut self.state, io)
+            .map_err(|reason| VmError::Fault { pc, reason })?;
+        match flow {
+            Flow::Next => self.state.pc = pc + 1,
+            Flow::Jump(offset) => {
+                self.state.pc = match (pc as i64).checked_add(offset) {
+                    Some(target) if target >= 0 && target <= len as i64 => target as usize,
+                    _ => return Err(VmError::OutOfBounds { pc, target: (pc as i64).saturating_add(offset) }),
+                };
+            }
+            Flow::Halt => return Ok(Some(VmExit::Halted)),
+        }
+        return Ok(None);
+    }
+
+    /// Runs the instruction at `pc`, or returns why the machine stopped.
+    pub fn step(&mut self, program: &[VmInstruction], io: &mut dyn VmIo) -> Result<Option<VmExit>, VmError> {
+        if let Some(exit) = self.stop_reason(program.len()) {
+            return Ok(Some(exit));
+        }
+        return self.execute(&program[self.state.pc], program.len(), io);
+    }
+
+    pub fn run(&mut self, program: &[VmInstruction], io: &mut dyn VmIo) -> Result<VmExit, VmError> {
+        loop {
+            if let Some(exit) = self.step(program, io)? {
+                return Ok(exit);
+            }
+        }
+    }
+}
+
+
+fn example_program() -> Program {
+    Program::parse("nop +0
 acc +1
 jmp +4
 acc +3
@@ -139,10 +1356,399 @@ jmp -3
 acc -99
 acc +1
 jmp -4
-acc +6".lines().map(|s| s.to_string() ).collect();
-    assert_eq!(calculate_accumulator(&instructions), 5);
-    let working = find_working_instruction(&instructions);
+acc +6").unwrap()
+}
+
+fn test_calculate_accumulator() {
+    let program = example_program();
+    assert_eq!(calculate_accumulator(&program), 5);
+    let repair = find_working_instruction(&program).unwrap();
+    assert_eq!(repair.to_string(), "line 8: jmp -4 -> nop -4");
+    assert_eq!(repair.result.accumulator, 8);
+    assert_eq!(calculate_accumulator(&repair.apply(&program)), 8);
+    assert_eq!(find_working_instruction_linear(&program), Some(repair));
+
+}
+
+fn test_parse_instruction() {
+    assert_eq!(Instruction::parse("acc +1"), Ok(Instruction::Acc(1)));
+    assert_eq!(Instruction::parse("jmp -3"), Ok(Instruction::Jmp(-3)));
+    assert_eq!(Instruction::parse("nop +0"), Ok(Instruction::Nop(0)));
+    assert_eq!(Instruction::parse("acc 7"), Ok(Instruction::Acc(7)));
+    assert_eq!(Instruction::parse("mul +2"), Err("unknown opcode \"mul\"".to_string()));
+    assert_eq!(Instruction::parse(""), Err("unknown opcode \"\"".to_string()));
+    assert_eq!(Instruction::parse("jmp"), Err("missing argument for jmp".to_string()));
+    assert_eq!(Instruction::parse("acc +x"), Err("invalid argument \"+x\"".to_string()));
+    assert_eq!(Instruction::parse("acc +1 +2"), Err("unexpected \"+2\" after argument".to_string()));
+
+    assert_eq!(Instruction::Jmp(-3).to_string(), "jmp -3");
+    assert_eq!(Instruction::Nop(0).to_string(), "nop +0");
+}
+
+fn test_parse_program() {
+    let program = example_program();
+    assert_eq!(program.len(), 9);
+    assert_eq!(program.get(2), Some(Instruction::Jmp(4)));
+    assert_eq!(Program::parse(&program.to_string()), Ok(program));
+    assert_eq!(Program::parse("acc +1\njmp -1").unwrap(),
+        Program::new(vec![Instruction::Acc(1), Instruction::Jmp(-1)]));
+
+    assert_eq!(Program::parse("nop +0\nacc +1\nhalt\njmp -2"), Err(ProgramError {
+        line: 3,
+        text: "halt".to_string(),
+        reason: "unknown opcode \"halt\"".to_string(),
+    }));
+    assert_eq!(Program::parse("acc +1\nacc one").unwrap_err().to_string(),
+        "line 2: invalid argument \"one\" in \"acc one\"");
+}
+
+fn test_vm_boot_code() {
+    let set = InstructionSet::boot_code();
+    let program = set.compile(&example_program()).unwrap();
+    let mut io = BufferedIo::default();
+    let mut vm = Vm::new(&set).with_repeat_detection();
+    assert_eq!(vm.run(&program, &mut io), Ok(VmExit::Repeated { pc: 1 }));
+    assert_eq!(vm.register("acc"), Some(5));
+    assert_eq!(vm.steps, 7);
+
+    let mut vm = Vm::new(&set).with_step_limit(100);
+    assert_eq!(vm.run(&program, &mut io), Ok(VmExit::StepLimit));
+    assert_eq!(vm.steps, 100);
+
+    let program = set.assemble("acc +2\njmp +2\nacc +5\nnop +0").unwrap();
+    let mut vm = Vm::new(&set);
+    assert_eq!(vm.run(&program, &mut io), Ok(VmExit::Terminated));
+    assert_eq!(vm.register("acc"), Some(2));
 
-    assert_eq!(calculate_accumulator(&working), 8);
+    let program = set.assemble("nop +0\njmp -2").unwrap();
+    assert_eq!(Vm::new(&set).run(&program, &mut io), Err(VmError::OutOfBounds { pc: 1, target: -1 }));
+    let program = set.assemble("nop +0\njmp +9223372036854775807").unwrap();
+    let mut vm = Vm::new(&set);
+    assert_eq!(vm.run(&program, &mut io), Err(VmError::OutOfBounds { pc: 1, target: i64::MAX }));
+    assert_eq!((vm.state.pc, vm.steps), (1, 2));
 
-}
\ No newline at end of file
+    let mut vm = Vm::new(&set).with_repeat_detection();
+    let program = set.compile(&example_program()).unwrap();
+    assert_eq!(vm.step(&program, &mut io), Ok(None));
+    assert_eq!(vm.step(&program, &mut io), Ok(None));
+    assert_eq!(vm.register("acc"), Some(1));
+    vm.reset();
+    assert_eq!((vm.state.pc, vm.register("acc"), vm.steps), (0, Some(0), 0));
+    assert_eq!(vm.run(&program, &mut io), Ok(VmExit::Repeated { pc: 1 }));
+
+    let nop_only = InstructionSet::new(&["acc"]).register(Box::new(NopOpcode));
+    assert_eq!(nop_only.compile(&example_program()), Err(ProgramError {
+        line: 2,
+        text: "acc +1".to_string(),
+        reason: "unknown opcode \"acc\"".to_string(),
+    }));
+
+    assert_eq!(set.assemble("add a 1").unwrap_err().reason, "unknown opcode \"add\"");
+    assert_eq!(set.assemble("jmp").unwrap_err().reason, "jmp takes 1 operands, found 0");
+}
+
+fn test_vm_extended() {
+    let set = InstructionSet::extended();
+    // Multiplies two inputs by repeated addition and prints the product.
+    let program = set.assemble("in a
+in b
+jz b +4
+add c a
+add b -1
+jmp -3
+out c
+hlt
+out -1").unwrap();
+    let mut io = BufferedIo::new(vec![6, 7]);
+    let mut vm = Vm::new(&set).with_step_limit(1000);
+    assert_eq!(vm.run(&program, &mut io), Ok(VmExit::Halted));
+    assert_eq!(io.output, vec![42]);
+    assert_eq!(vm.register("b"), Some(0));
+    assert_eq!(vm.register("z"), None);
+
+    let mut io = BufferedIo::new(vec![6]);
+    assert_eq!(Vm::new(&set).run(&program, &mut io), Err(VmError::Fault {
+        pc: 1,
+        reason: "input exhausted".to_string(),
+    }));
+
+    let program = set.assemble("set 1 a").unwrap();
+    assert_eq!(Vm::new(&set).run(&program, &mut io).unwrap_err().to_string(),
+        "instruction 0: cannot write to literal +1");
+
+    let program = set.assemble("set a 9223372036854775807\nadd a 1").unwrap();
+    assert_eq!(Vm::new(&set).run(&program, &mut io), Err(VmError::Fault {
+        pc: 1,
+        reason: "arithmetic overflow".to_string(),
+    }));
+}
+
+/// `dbl r`: doubles register `r`.
+struct DoubleOpcode;
+
+impl Opcode for DoubleOpcode {
+    fn name(&self) -> &str { return "dbl"; }
+    fn arity(&self) -> usize { return 1; }
+    fn execute(&self, operands: &[Operand], state: &mut VmState, _io: &mut dyn VmIo) -> Result<Flow, String> {
+        state.update(operands[0], 2, i64::checked_mul)?;
+        return Ok(Flow::Next);
+    }
+}
+
+fn test_vm_custom_opcode() {
+    let set = InstructionSet::boot_code().register(Box::new(DoubleOpcode));
+    let program = set.assemble("acc +3\ndbl acc\ndbl acc").unwrap();
+    let mut vm = Vm::new(&set);
+    assert_eq!(vm.run(&program, &mut BufferedIo::default()), Ok(VmExit::Terminated));
+    assert_eq!(vm.register("acc"), Some(12));
+}
+
+fn test_run_outcomes() {
+    let program = example_program();
+    let mut code = BootCode::new(&program);
+    assert_eq!(code.run(), RunResult {
+        outcome: RunOutcome::InfiniteLoop { at: 1 },
+        accumulator: 5,
+        index_pointer: 1,
+        steps: 7,
+    });
+    assert!(code.does_loop());
+    assert!(!code.terminates());
+    assert_eq!(code.run().accumulator, 5);
+
+    let program = Program::parse("nop +0\nacc +1\njmp +4\nacc +3\njmp -3\nacc -99\nacc +1\nnop -4\nacc +6").unwrap();
+    assert_eq!(BootCode::new(&program).run(), RunResult {
+        outcome: RunOutcome::Terminated,
+        accumulator: 8,
+        index_pointer: 9,
+        steps: 6,
+    });
+    assert!(!BootCode::new(&program).does_loop());
+    assert!(BootCode::new(&program).terminates());
+
+    let program = Program::parse("acc +1\njmp -2").unwrap();
+    assert_eq!(BootCode::new(&program).run(), RunResult {
+        outcome: RunOutcome::OutOfBounds { target: -1 },
+        accumulator: 1,
+        index_pointer: 1,
+        steps: 2,
+    });
+    assert!(!BootCode::new(&program).does_loop());
+    assert!(!BootCode::new(&program).terminates());
+
+    let program = Program::parse("acc +1\njmp +2").unwrap();
+    assert_eq!(BootCode::new(&program).run().outcome, RunOutcome::OutOfBounds { target: 3 });
+    let program = Program::parse("acc +1\njmp +1").unwrap();
+    assert_eq!(BootCode::new(&program).run().outcome, RunOutcome::Terminated);
+
+    let program = example_program();
+    let mut code = BootCode::new(&program).with_step_limit(3);
+    assert_eq!(code.run(), RunResult {
+        outcome: RunOutcome::StepLimit,
+        accumulator: 1,
+        index_pointer: 6,
+        steps: 3,
+    });
+
+    assert_eq!(BootCode::new(&Program::new(vec![])).run().outcome, RunOutcome::Terminated);
+
+    let program = Program::parse("acc +9223372036854775807\nacc +1").unwrap();
+    assert_eq!(BootCode::new(&program).run(), RunResult {
+        outcome: RunOutcome::Overflow,
+        accumulator: i64::MAX,
+        index_pointer: 1,
+        steps: 2,
+    });
+    assert_eq!(RunOutcome::Overflow.to_string(), "accumulator overflow");
+
+    let program = Program::parse("nop +0\njmp +9223372036854775807").unwrap();
+    assert_eq!(BootCode::new(&program).run(), RunResult {
+        outcome: RunOutcome::OutOfBounds { target: i64::MAX },
+        accumulator: 0,
+        index_pointer: 1,
+        steps: 2,
+    });
+    let program = Program::parse("jmp -9223372036854775808").unwrap();
+    assert_eq!(BootCode::new(&program).run().outcome, RunOutcome::OutOfBounds { target: i64::MIN });
+}
+
+fn test_repair() {
+    // The nop needs its argument kept: a bare `jmp +0` would loop on itself.
+    let program = Program::parse("nop +2\njmp +0\nacc +1").unwrap();
+    let repair = find_working_instruction(&program).unwrap();
+    assert_eq!(repair.to_string(), "line 1: nop +2 -> jmp +2");
+    assert_eq!(repair.result.accumulator, 1);
+    assert_eq!(find_working_instruction_linear(&program), Some(repair));
+
+    // Flipping line 1 jumps out of bounds, so only line 3 fixes it.
+    let program = Program::parse("nop -5\nacc +2\njmp -2\nacc +4").unwrap();
+    let repair = find_working_instruction(&program).unwrap();
+    assert_eq!((repair.index, repair.patched), (2, Instruction::Nop(-2)));
+    assert_eq!(repair.result.accumulator, 6);
+    assert_eq!(find_working_instruction_linear(&program), Some(repair));
+
+    let program = Program::parse("acc +1\njmp +0").unwrap();
+    assert_eq!(find_working_instruction(&program).map(|repair| repair.index), Some(1));
+    let program = Program::parse("jmp +0\njmp -1").unwrap();
+    assert_eq!(find_working_instruction(&program), None);
+    assert_eq!(find_working_instruction_linear(&program), None);
+
+    // Flipping line 1 leads to line 1 itself, and flipping line 2 leads back
+    // through line 2; neither may be reported as a repair.
+    let program = Program::parse("nop +0\nnop -1\nacc +1").unwrap();
+    assert_eq!(find_working_instruction(&program), None);
+    assert_eq!(find_working_instruction_linear(&program), None);
+    let program = Program::parse("nop +0").unwrap();
+    assert_eq!(find_working_instruction(&program), None);
+    assert_eq!(find_working_instruction_linear(&program), None);
+
+    // Flipping line 5 reaches the end statically, but an acc overflows first.
+    let program = Program::parse("jmp +2\njmp +3\nacc +9223372036854775807\nacc +1\njmp -3").unwrap();
+    assert_eq!(find_working_instruction(&program), None);
+    assert_eq!(find_working_instruction_linear(&program), None);
+
+    assert_eq!(example_program().terminating_instructions(),
+        vec![false, false, false, false, false, false, false, false, true, true]);
+}
+
+fn test_trace() {
+    let (trace, result) = BootCode::new(&example_program()).trace();
+    assert_eq!(trace.len(), result.steps);
+    assert_eq!(trace[1], TraceStep {
+        index: 1,
+        instruction: Instruction::Acc(1),
+        accumulator_before: 0,
+        accumulator_after: 1,
+    });
+    assert_eq!(trace[1].to_string(), "line    2: acc +1    acc 0 -> 1");
+    assert_eq!(result.outcome.to_string(), "infinite loop: line 2 would run again");
+
+    let cycle = loop_cycle(&trace, result.outcome).unwrap();
+    assert_eq!(cycle.iter().map(|step| step.index).collect::<Vec<usize>>(), vec![1, 2, 6, 7, 3, 4]);
+    assert_eq!(format_loop_cycle(cycle).lines().next(),
+        Some("loop of 6 instructions entered at line 2, acc +5 per pass"));
+
+    let program = Program::parse("acc +1\njmp +1").unwrap();
+    let (trace, result) = BootCode::new(&program).trace();
+    assert_eq!(trace.len(), 2);
+    assert_eq!(loop_cycle(&trace, result.outcome), None);
+}
+
+fn test_debugger() {
+    let program = example_program();
+    let mut debugger = Debugger::new(&program);
+    assert_eq!(debugger.command("p"), "line 1: nop +0  acc 0  steps 0\n");
+    assert_eq!(debugger.command("step 2"), "line    1: nop +0    acc 0 -> 0\nline    2: acc +1    acc 0 -> 1\n");
+    assert_eq!(debugger.command("break 5"), "breakpoint at line 5\n");
+    assert_eq!(debugger.command("break 10"), "line 10 is outside the program (1-9)\n");
+    assert_eq!(debugger.command("c"), "breakpoint at line 5\nline 5: jmp -3  acc 5  steps 6\n");
+    assert_eq!(debugger.command("list 1"), "      4  acc +3\n>*    5  jmp -3\n      6  acc -99\n");
+    assert_eq!(debugger.command("loop"), "no loop detected\n");
+    assert_eq!(debugger.command("continue"), "infinite loop: line 2 would run again\n");
+    assert_eq!(debugger.command("s"), "infinite loop: line 2 would run again\n");
+    assert!(debugger.command("loop").starts_with("loop of 6 instructions entered at line 2"));
+    assert_eq!(debugger.command("delete 5"), "removed breakpoint at line 5\n");
+    assert_eq!(debugger.command("restart"), "line 1: nop +0  acc 0  steps 0\n");
+    assert_eq!(debugger.command("step 3").lines().count(), 3);
+    assert_eq!(debugger.command("jump"), "unknown command \"jump\", try help\n");
+    assert_eq!(debugger.command(""), DEBUGGER_HELP);
+    assert!(!debugger.has_quit());
+    assert_eq!(debugger.command("q"), "");
+    assert!(debugger.has_quit());
+    let mut debugger = Debugger::new(&program);
+    assert_eq!(debugger.command("quit"), "");
+    assert!(debugger.has_quit());
+
+    let program = Program::parse("acc +1\njmp +1").unwrap();
+    let mut debugger = Debugger::new(&program);
+    assert_eq!(debugger.command("c"), "program terminated\n");
+    assert_eq!(debugger.command("p"), "end of program  acc 1  steps 2\n");
+}
+
+fn test_static_analysis() {
+    let program = example_program();
+    let analysis = StaticAnalysis::new(&program);
+    assert_eq!(analysis.path, vec![0, 1, 2, 6, 7, 3, 4]);
+    assert_eq!(analysis.outcome, RunOutcome::InfiniteLoop { at: 1 });
+    assert_eq!(analysis.cycle, vec![1, 2, 6, 7, 3, 4]);
+    assert_eq!(analysis.unreachable(), vec![5, 8]);
+    assert_eq!(analysis.terminating_flips, vec![7]);
+    assert_eq!(analysis.terminating_flips.iter().map(|&index| Some(index)).collect::<Vec<Option<usize>>>(),
+        vec![find_working_instruction(&program).map(|repair| repair.index)]);
+
+    // Line 1 flipped jumps to itself. Line 2 flipped jumps to line 1, which
+    // leads to the end in the original program but only through line 2.
+    let program = Program::parse("nop +0\nnop -1\nacc +1").unwrap();
+    let analysis = StaticAnalysis::new(&program);
+    assert_eq!(analysis.outcome, RunOutcome::Terminated);
+    assert_eq!(analysis.cycle, vec![]);
+    assert_eq!(analysis.terminating_flips, vec![]);
+    let program = Program::parse("nop +0\nnop +1\njmp +1").unwrap();
+    assert_eq!(StaticAnalysis::new(&program).terminating_flips, vec![1, 2]);
+
+    let program = Program::parse("acc +1\njmp -2\nacc +1").unwrap();
+    let analysis = StaticAnalysis::new(&program);
+    assert_eq!(analysis.outcome, RunOutcome::OutOfBounds { target: -1 });
+    assert_eq!(analysis.successors, vec![Some(1), None, Some(3)]);
+    assert_eq!(analysis.terminating_flips, vec![1]);
+
+    let program = Program::parse("jmp +0").unwrap();
+    assert_eq!(StaticAnalysis::new(&program).terminating_flips, vec![0]);
+    let program = Program::parse("nop +0\njmp -1").unwrap();
+    assert_eq!(StaticAnalysis::new(&program).terminating_flips, vec![1]);
+    let program = Program::parse("jmp +1\njmp -1").unwrap();
+    assert_eq!(StaticAnalysis::new(&program).terminating_flips, vec![1]);
+
+    // The same flips as running every patched program.
+    for source in &["nop +0\nnop -1\nacc +1", "nop +0\nnop +1\njmp +1", "jmp +2\nnop -1\njmp -1\nacc +1",
+        "nop +3\njmp +2\njmp -2\nnop -2\njmp -4", "nop +0", "jmp +1\nnop +1\njmp -2\njmp +0"] {
+        let program = Program::parse(source).unwrap();
+        let (path, _) = program.execution_path();
+        let expected: Vec<usize> = path.into_iter().filter(|&index| {
+            program.instructions[index].flipped().is_some_and(|flipped| {
+                let mut patched = program.clone();
+                patched.instructions[index] = flipped;
+                BootCode::new(&patched).run().outcome == RunOutcome::Terminated
+            })
+        }).collect();
+        assert_eq!(program.terminating_flips(), expected, "{:?}", source);
+    }
+
+    // A jump too far for an i64 is out of bounds rather than a panic.
+    let program = Program::parse("nop +0\njmp +9223372036854775807").unwrap();
+    let analysis = StaticAnalysis::new(&program);
+    assert_eq!(analysis.outcome, RunOutcome::OutOfBounds { target: i64::MAX });
+    assert_eq!(analysis.successors, vec![Some(1), None]);
+    assert_eq!(analysis.terminating_flips, vec![1]);
+    assert!(analysis.to_dot(&program).contains("    n1 -> out;\n"));
+    let program = Program::parse("jmp -9223372036854775808").unwrap();
+    assert_eq!(program.successor(0, Instruction::Jmp(i64::MIN)), None);
+    assert_eq!(program.execution_path(), (vec![0], RunOutcome::OutOfBounds { target: i64::MIN }));
+
+    assert_eq!(line_ranges(&[8, 2, 3, 4, 0]), "1, 3-5, 9");
+    assert_eq!(line_ranges(&[]), "");
+}
+
+fn test_analysis_output() {
+    let program = example_program();
+    let analysis = StaticAnalysis::new(&program);
+    assert_eq!(analysis.to_report(&program), "instructions 9
+reachable 7
+unreachable 2: 6, 9
+infinite loop of 6 instructions entered at line 2: 2-5, 7-8
+terminating flips 1
+  line 8: jmp -4 -> nop -4
+");
+
+    let dot = analysis.to_dot(&program);
+    assert!(dot.starts_with("digraph {\n"));
+    assert!(dot.contains("    n0 [label=\"1: nop +0\"];\n"));
+    assert!(dot.contains("    n1 [label=\"2: acc +1\", color=red];\n"));
+    assert!(dot.contains("    n5 [label=\"6: acc -99\", color=grey, fontcolor=grey];\n"));
+    assert!(dot.contains("    n2 -> n6;\n"));
+    assert!(dot.contains("    n8 -> end;\n"));
+    assert!(dot.contains("    n7 -> n8 [style=dashed, color=green, label=\"nop -4\"];\n"));
+
+    let program = Program::parse("jmp -1").unwrap();
+    assert!(StaticAnalysis::new(&program).to_dot(&program).contains("    n0 -> out;\n"));
+}
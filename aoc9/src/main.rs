@@ -1,105 +1,252 @@
-#![feature(deque_range)]
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::env;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader};
+use std::ops::Index;
+use std::process;
 
 fn main() {
     test_find_invalid_sums();
-    let numbers = read_data("src/input.txt");
-    let invalid = find_invalid_sums_number(&numbers, 25);
-    println!("Invalid number {}", invalid);
-
-    let mut set = find_contiguous_set_adds_to_sum(&numbers, invalid);
-    let weakness = add_min_and_max(&mut set);
-    println!("Encryption weakness {}", weakness);
-}
-
-
-fn read_data(filename: &str) -> Vec<u128> {
-    let file = File::open(filename)
-    .expect("Something went wrong reading the file");
-    let reader = BufReader::new(file);
-    let numbers = reader.lines().map(|line| line.unwrap().parse().unwrap()).collect();
-
-    return numbers;
-}
-
-fn find_contiguous_set_adds_to_sum(numbers: &Vec<u128>, target: u128) -> Vec<u128> {
-    let mut shortened_numbers_reverse = numbers.clone();
-    shortened_numbers_reverse.truncate(numbers.iter().position(|n| n == &target).unwrap());
-    shortened_numbers_reverse.reverse();
-    let mut shortened_numbers_reverse_queue = VecDeque::from(shortened_numbers_reverse.clone());
-
-    let mut found_set = Vec::new();
-    loop {
-        let mut sum = 0;
-        let mut over = false;
-        let mut found = false;
-        let mut depth = 0;
-        for number in shortened_numbers_reverse_queue.iter() {
-            depth += 1;
-            sum += number;
-            if sum == target {
-                found = true;
-                break;
+    test_validator_pairs();
+    test_invalid_numbers();
+    test_contiguous_range();
+    test_monitor();
+
+    // aoc9 [input|-] [preamble] [history]
+    let args: Vec<String> = env::args().collect();
+    let input = args.get(1).map_or("src/input.txt", |input| input.as_str());
+    let preamble = args.get(2).map_or(Ok(25), |preamble| preamble.parse()).unwrap_or_else(|_| {
+        eprintln!("preamble must be a number");
+        process::exit(2);
+    });
+    let history = args.get(3).map_or(Ok(10_000), |history| history.parse()).unwrap_or_else(|_| {
+        eprintln!("history must be a number");
+        process::exit(2);
+    });
+
+    let reader: Box<dyn BufRead> = match input {
+        "-" => Box::new(BufReader::new(io::stdin())),
+        filename => match File::open(filename) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("{}: {}", filename, e);
+                process::exit(1);
             }
-            else if sum > target {
-                over = true;
-                break;
+        },
+    };
+    if let Err(e) = monitor_stream(reader, XmasMonitor::new(preamble, history)) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Checks numbers one line at a time as they arrive, reporting each
+/// invalid one and its weakness straight away. Blank lines are skipped.
+fn monitor_stream<R: BufRead>(reader: R, mut monitor: XmasMonitor) -> Result<(), String> {
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let value: u128 = line.parse()
+            .map_err(|_| format!("line {}: invalid number {:?}", line_number + 1, line))?;
+
+        if let Some(invalid) = monitor.observe(value) {
+            println!("Invalid number {} at index {}", invalid.value, invalid.index);
+            match monitor.weakness(invalid.value) {
+                Some(weakness) => println!("Encryption weakness {} from indexes {}-{}",
+                    weakness.smallest + weakness.largest, weakness.start, weakness.end),
+                None => println!("No encryption weakness in the last {} numbers", monitor.history.len()),
             }
         }
+    }
+    return Ok(());
+}
+
+/// A run of at least two consecutive numbers adding up to an invalid one.
+/// `start` and `end` are inclusive stream indexes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Weakness {
+    start: usize,
+    end: usize,
+    smallest: u128,
+    largest: u128,
+}
+
+/// Validates a stream one number at a time and remembers the most recent
+/// `history_limit` numbers for finding weaknesses later.
+struct XmasMonitor {
+    validator: XmasValidator,
+    history: VecDeque<u128>,
+    history_limit: usize,
+    seen: usize,
+}
 
-        if over {
-            shortened_numbers_reverse_queue.pop_front();
-            over = false;
-            sum = 0;
-            depth = 0;
+impl XmasMonitor {
+    pub fn new(preamble: usize, history_limit: usize) -> XmasMonitor {
+        XmasMonitor {
+            validator: XmasValidator::new(preamble),
+            history: VecDeque::with_capacity(history_limit),
+            history_limit,
+            seen: 0,
         }
+    }
 
-        if found {
-            shortened_numbers_reverse_queue.truncate(depth);
-            found_set = Vec::from(shortened_numbers_reverse_queue);
-            break;
+    /// Checks the next number, returning it if it is invalid.
+    pub fn observe(&mut self, value: u128) -> Option<InvalidNumber> {
+        let index = self.seen;
+        let valid = self.validator.push(value);
+        self.seen += 1;
+        if self.history_limit > 0 {
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(value);
         }
 
+        match valid {
+            true => None,
+            false => Some(InvalidNumber { index, value }),
+        }
     }
-    return found_set;
+
+    /// Searches the remembered numbers for a run adding up to `target`,
+    /// stopping before `target` itself as `find_contiguous_set_adds_to_sum`
+    /// does, so a run can never include the invalid number.
+    pub fn weakness(&self, target: u128) -> Option<Weakness> {
+        let before = self.history.iter().position(|&number| number == target).unwrap_or(self.history.len());
+        let (start, end) = contiguous_range(&self.history, before, target)?;
+        let run = self.history.range(start..=end);
+        let offset = self.seen - self.history.len();
+        return Some(Weakness {
+            start: start + offset,
+            end: end + offset,
+            smallest: *run.clone().min().unwrap(),
+            largest: *run.max().unwrap(),
+        });
+    }
+}
+
+/// Finds the first run of at least two consecutive numbers among the first
+/// `length` of `numbers` that adds up to `target`, as an inclusive index
+/// range. One pass with a sliding window, since no number is negative.
+fn contiguous_range<N>(numbers: &N, length: usize, target: u128) -> Option<(usize, usize)>
+where N: Index<usize, Output = u128> + ?Sized {
+    let mut start = 0;
+    let mut sum: u128 = 0;
+    for end in 0..length {
+        while start < end && sum.checked_add(numbers[end]).is_none_or(|sum| sum > target) {
+            sum -= numbers[start];
+            start += 1;
+        }
+        sum += numbers[end];
+        if sum == target && start < end {
+            return Some((start, end));
+        }
+    }
+    return None;
 }
 
-fn add_min_and_max(numbers: &mut Vec<u128>) -> u128 {
-    numbers.sort();
-    return numbers.first().unwrap() + numbers.last().unwrap();
+/// The run adding up to `target` among the numbers before its first
+/// appearance, or among all of them if it does not appear.
+fn find_contiguous_set_adds_to_sum(numbers: &[u128], target: u128) -> Option<&[u128]> {
+    let before = numbers.iter().position(|&number| number == target).unwrap_or(numbers.len());
+    let (start, end) = contiguous_range(numbers, before, target)?;
+    return Some(&numbers[start..=end]);
+}
 
+fn add_min_and_max(numbers: &[u128]) -> u128 {
+    return numbers.iter().min().unwrap() + numbers.iter().max().unwrap();
 }
 
-fn find_invalid_sums_number(numbers: &Vec<u128>, buffer: usize) -> u128 {
-    let mut last_values_buffer = VecDeque::with_capacity(buffer);
-    for initial_buffer in numbers[..buffer].iter() {
-        last_values_buffer.push_back(initial_buffer);
+/// Sliding window over the last `preamble` numbers that keeps a count of
+/// every sum of two different values in it, so checking a number is one
+/// lookup and sliding the window costs one pass over it. Memory grows with
+/// the square of the preamble.
+struct XmasValidator {
+    preamble: usize,
+    window: VecDeque<u128>,
+    pair_sums: HashMap<u128, usize>,
+}
+
+impl XmasValidator {
+    pub fn new(preamble: usize) -> XmasValidator {
+        XmasValidator {
+            preamble,
+            window: VecDeque::with_capacity(preamble + 1),
+            pair_sums: HashMap::new(),
+        }
     }
-    let mut invalid: u128 = 0;
-    for check_number in numbers[buffer..].iter() {
-        let mut valid_number = false;
-        for (index_1, buffer_number_1) in last_values_buffer.iter().enumerate() {
-            for buffer_number_2 in last_values_buffer.range(index_1..) {
-                if *buffer_number_1 + *buffer_number_2 == *check_number {
-                    valid_number = true;
-                    break;
+
+    /// Whether `number` is the sum of two different values in a full window.
+    pub fn is_valid(&self, number: u128) -> bool {
+        self.window.len() == self.preamble && self.pair_sums.contains_key(&number)
+    }
+
+    /// Checks `number` against the window, then slides it into the window.
+    /// Numbers still filling the preamble always count as valid.
+    pub fn push(&mut self, number: u128) -> bool {
+        let valid = self.window.len() < self.preamble || self.is_valid(number);
+        if self.preamble == 0 {
+            return valid;
+        }
+
+        if self.window.len() == self.preamble {
+            let oldest = self.window.pop_front().unwrap();
+            for &other in &self.window {
+                if let Some(sum) = pair_sum(oldest, other) {
+                    let count = self.pair_sums.get_mut(&sum).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        self.pair_sums.remove(&sum);
+                    }
                 }
             }
         }
-        if !valid_number {
-            invalid = *check_number;
-            break;
+        for &other in &self.window {
+            if let Some(sum) = pair_sum(number, other) {
+                *self.pair_sums.entry(sum).or_insert(0) += 1;
+            }
         }
-        last_values_buffer.pop_front();
-        last_values_buffer.push_back(check_number);
+        self.window.push_back(number);
+
+        return valid;
+    }
+}
+
+/// The sum of two different values, or `None` if they are equal or the sum
+/// does not fit, since no number could match it.
+fn pair_sum(a: u128, b: u128) -> Option<u128> {
+    if a == b {
+        return None;
     }
-    return invalid;
+    return a.checked_add(b);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct InvalidNumber {
+    index: usize,
+    value: u128,
+}
+
+/// Every number that is not the sum of two different values among the
+/// `preamble` numbers before it, with its index in the input.
+fn invalid_numbers<I>(numbers: I, preamble: usize) -> impl Iterator<Item = InvalidNumber>
+where I: IntoIterator<Item = u128> {
+    let mut validator = XmasValidator::new(preamble);
+    return numbers.into_iter()
+        .enumerate()
+        .filter(move |&(_, value)| !validator.push(value))
+        .map(|(index, value)| InvalidNumber { index, value });
+}
+
+fn find_invalid_sums_number(numbers: &[u128], preamble: usize) -> Option<InvalidNumber> {
+    return invalid_numbers(numbers.iter().cloned(), preamble).next();
 }
 
 fn test_find_invalid_sums() {
-    let input = "35
+    let input: Vec<u128> = "35
 20
 15
 25
@@ -119,10 +266,115 @@ fn test_find_invalid_sums() {
 277
 309
 576".lines().map(|n| n.parse().unwrap()).collect();
-        let invalid = find_invalid_sums_number(&input, 5);
-        assert_eq!(invalid, 127);
+        let invalid = find_invalid_sums_number(&input, 5).unwrap();
+        assert_eq!(invalid, InvalidNumber { index: 14, value: 127 });
 
-        let mut set = find_contiguous_set_adds_to_sum(&input, invalid);
-        let weakness = add_min_and_max(&mut set);
+        let set = find_contiguous_set_adds_to_sum(&input, invalid.value).unwrap();
+        let weakness = add_min_and_max(set);
         assert_eq!(weakness, 62);
-}
\ No newline at end of file
+}
+
+fn test_validator_pairs() {
+    let mut validator = XmasValidator::new(3);
+    assert!(validator.push(1));
+    assert!(validator.push(2));
+    assert!(validator.push(2));
+    assert!(validator.is_valid(3));
+    // 4 would need 2 twice, and the two values must differ.
+    assert!(!validator.is_valid(4));
+    assert!(validator.push(3));
+    assert!(validator.is_valid(5));
+    assert!(!validator.is_valid(3));
+    assert!(!validator.push(1));
+    assert_eq!(validator.window, vec![2, 3, 1]);
+    assert_eq!(validator.pair_sums.len(), 3);
+
+    let mut validator = XmasValidator::new(2);
+    validator.push(u128::MAX);
+    validator.push(1);
+    assert!(!validator.is_valid(0));
+    assert!(!validator.push(u128::MAX));
+
+    let mut validator = XmasValidator::new(0);
+    assert!(!validator.push(0));
+}
+
+fn test_invalid_numbers() {
+    let numbers: Vec<u128> = (1..=25).chain(vec![26, 49, 100, 200, 125]).collect();
+    assert_eq!(invalid_numbers(numbers.clone(), 25).collect::<Vec<InvalidNumber>>(), vec![
+        InvalidNumber { index: 27, value: 100 },
+        InvalidNumber { index: 28, value: 200 },
+    ]);
+    assert_eq!(find_invalid_sums_number(&numbers[..27], 25), None);
+
+    // Matches a direct check of every pair on a scrambled stream.
+    let mut state: u64 = 0x2545F4914F6CDD1D;
+    let numbers: Vec<u128> = (0..2000).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % 400) as u128
+    }).collect();
+    for &preamble in &[1, 2, 7, 25, 100] {
+        let expected: Vec<InvalidNumber> = (preamble..numbers.len())
+            .filter(|&index| {
+                let window = &numbers[index - preamble..index];
+                !window.iter().any(|&a| window.iter().any(|&b| a != b && a + b == numbers[index]))
+            })
+            .map(|index| InvalidNumber { index, value: numbers[index] })
+            .collect();
+        assert_eq!(invalid_numbers(numbers.clone(), preamble).collect::<Vec<InvalidNumber>>(), expected);
+    }
+}
+
+fn test_contiguous_range() {
+    let numbers: Vec<u128> = vec![3, 1, 4, 1, 5, 9, 2, 6];
+    assert_eq!(contiguous_range(&numbers, numbers.len(), 10), Some((2, 4)));
+    assert_eq!(contiguous_range(&numbers, numbers.len(), 17), Some((3, 6)));
+    assert_eq!(contiguous_range(&numbers, numbers.len(), 9), Some((0, 3)));
+    // A single number equal to the target does not count.
+    assert_eq!(contiguous_range(&vec![1, 6, 7], 3, 6), None);
+    assert_eq!(contiguous_range(&numbers, numbers.len(), 100), None);
+    assert_eq!(contiguous_range(&numbers, 3, 10), None);
+    assert_eq!(contiguous_range(&vec![u128::MAX, 1, 2], 3, 3), Some((1, 2)));
+
+    assert_eq!(find_contiguous_set_adds_to_sum(&[1, 2, 3, 3], 3), Some(&[1, 2][..]));
+    assert_eq!(find_contiguous_set_adds_to_sum(&[3, 1, 2], 3), None);
+    assert_eq!(find_contiguous_set_adds_to_sum(&[5, 1, 2], 3), Some(&[1, 2][..]));
+}
+
+fn test_monitor() {
+    let stream = "35\n20\n15\n25\n47\n40\n62\n55\n65\n95\n102\n117\n150\n182\n127\n219\n299\n277\n309\n576";
+    let mut monitor = XmasMonitor::new(5, 100);
+    let invalid: Vec<InvalidNumber> = stream.lines()
+        .filter_map(|line| monitor.observe(line.parse().unwrap()))
+        .collect();
+    assert_eq!(invalid, vec![InvalidNumber { index: 14, value: 127 }]);
+    assert_eq!(monitor.weakness(127), Some(Weakness { start: 2, end: 5, smallest: 15, largest: 47 }));
+
+    // Only the last eight numbers are kept, which no longer covers the run.
+    let mut monitor = XmasMonitor::new(5, 8);
+    for line in stream.lines().take(15) {
+        monitor.observe(line.parse().unwrap());
+    }
+    assert_eq!(monitor.history.len(), 8);
+    assert_eq!(monitor.weakness(127), None);
+    assert_eq!(monitor.weakness(55 + 65), Some(Weakness { start: 7, end: 8, smallest: 55, largest: 65 }));
+
+    let mut monitor = XmasMonitor::new(2, 0);
+    assert_eq!(monitor.observe(1), None);
+    assert_eq!(monitor.observe(2), None);
+    assert_eq!(monitor.observe(4), Some(InvalidNumber { index: 2, value: 4 }));
+    assert_eq!(monitor.weakness(3), None);
+
+    // 0 + 5 adds up to the invalid 5, but that run ends on the 5 itself.
+    let mut monitor = XmasMonitor::new(2, 10);
+    let numbers = [1, 0, 5];
+    let invalid: Vec<InvalidNumber> = numbers.iter().filter_map(|&number| monitor.observe(number)).collect();
+    assert_eq!(invalid, vec![InvalidNumber { index: 2, value: 5 }]);
+    assert_eq!(monitor.weakness(5), None);
+    assert_eq!(find_contiguous_set_adds_to_sum(&numbers, 5), None);
+
+    assert_eq!(monitor_stream("1\n\n2\nthree".as_bytes(), XmasMonitor::new(2, 10)),
+        Err("line 4: invalid number \"three\"".to_string()));
+}